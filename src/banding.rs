@@ -0,0 +1,192 @@
+//! This module implements multi-index hashing, an approximate alternative
+//! to [`BkTree`](crate::bk_tree::BkTree) for very large collections.
+//!
+//! Each hash is split into a number of bands, and images sharing
+//! an identical band are bucketed together as candidate pairs.
+//! Only candidate pairs are then verified by their exact hamming distance.
+//!
+//! By the pigeonhole principle, if a hash is split into more bands than
+//! the threshold, any two hashes within the threshold must share at least
+//! one identical band, so no pair can be missed. Fewer (and therefore wider)
+//! bands produce fewer candidates, trading recall for speed.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use img_hash::ImageHash;
+
+/// Extract bits `[start, end)` of a hash into a compact key.
+/// A band must not be wider than [`MAX_BAND_WIDTH`].
+fn band_key(bytes: &[u8], start: usize, end: usize) -> u128 {
+    (start..end)
+        .enumerate()
+        .filter(|&(_, bit)| bytes[bit / 8] & (1 << (bit % 8)) != 0)
+        .fold(0, |key, (n, _)| key | 1 << n)
+}
+
+/// The maximum number of bits in a band, so that its key fits in a `u128`.
+const MAX_BAND_WIDTH: usize = 128;
+
+/// The bands of all hashes in a collection, and the buckets they fall into.
+#[derive(Debug)]
+pub struct BandIndex {
+    band_count: usize,
+    /// `keys[i * band_count + b]` is the key of band `b` of item `i`.
+    keys: Vec<u128>,
+    /// `buckets[b]` groups the items by their key of band `b`.
+    buckets: Vec<HashMap<u128, Vec<usize>>>,
+}
+impl BandIndex {
+    /// Split every hash into the specified number of bands, and bucket them.
+    ///
+    /// The number of bands is capped to the number of bits in the hashes,
+    /// and raised if necessary so that no band is wider than 128 bits.
+    /// If the threshold is not below the number of bits, the cap means
+    /// pairs may be missed; see [`SearchMode::fit_to_hashes`](crate::compute::SearchMode::fit_to_hashes).
+    pub fn new(img_hashes: &[(PathBuf, ImageHash)], band_count: usize) -> Self {
+        let bit_count = img_hashes
+            .first()
+            .map(|(_, hash)| hash.as_bytes().len() * 8)
+            .unwrap_or(0);
+        let band_count = band_count.min(bit_count).max(bit_count.div_ceil(MAX_BAND_WIDTH)).max(1);
+
+        let keys: Vec<_> = img_hashes
+            .iter()
+            .flat_map(|(_, hash)| {
+                let bytes = hash.as_bytes();
+                (0..band_count)
+                    .map(move |b| band_key(bytes, b * bit_count / band_count, (b + 1) * bit_count / band_count))
+            })
+            .collect();
+
+        let mut buckets = vec![HashMap::new(); band_count];
+        for (n, &key) in keys.iter().enumerate() {
+            buckets[n % band_count]
+                .entry(key)
+                .or_insert_with(Vec::new)
+                .push(n / band_count);
+        }
+
+        Self {
+            band_count,
+            keys,
+            buckets,
+        }
+    }
+
+    /// The number of bands each hash was split into.
+    pub fn band_count(&self) -> usize {
+        self.band_count
+    }
+
+    /// Iterate over all buckets with at least two items,
+    /// alongside the index of the band they belong to.
+    pub fn buckets(&self) -> impl Iterator<Item = (usize, &[usize])> {
+        self.buckets.iter().enumerate().flat_map(|(b, band_buckets)| {
+            band_buckets
+                .values()
+                .filter(|items| items.len() > 1)
+                .map(move |items| (b, items.as_slice()))
+        })
+    }
+
+    /// Two items may share identical keys in multiple bands.
+    /// To make sure each candidate pair is examined only once,
+    /// a pair is only considered a candidate in the first band they share.
+    pub fn is_first_shared_band(&self, i: usize, j: usize, band: usize) -> bool {
+        let (keys_i, keys_j) = (&self.keys[i * self.band_count..], &self.keys[j * self.band_count..]);
+        (0..band).all(|b| keys_i[b] != keys_j[b])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+    use std::collections::HashSet;
+
+    /// Random hashes of the given number of bytes, each a few random bit flips away
+    /// from one of a few centers, so that many pairs are near the threshold.
+    /// Uses xorshift, for reproducibility.
+    fn random_hashes(count: usize, byte_count: usize, seed: u64) -> Vec<(PathBuf, ImageHash)> {
+        let mut state = seed;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let centers: Vec<Vec<u8>> = (0..3)
+            .map(|_| (0..byte_count).map(|_| next() as u8).collect())
+            .collect();
+        (0..count)
+            .map(|n| {
+                let mut bytes = centers[n % centers.len()].clone();
+                for _ in 0..next() % 10 {
+                    let bit = next() as usize % (byte_count * 8);
+                    bytes[bit / 8] ^= 1 << (bit % 8);
+                }
+                (PathBuf::from(n.to_string()), ImageHash::from_bytes(&bytes).unwrap())
+            })
+            .collect()
+    }
+
+    /// All candidate pairs of the index, checking that none is reported twice.
+    fn candidate_pairs(index: &BandIndex) -> HashSet<(usize, usize)> {
+        let mut candidates = HashSet::new();
+        for (band, items) in index.buckets() {
+            for (n, &i) in items.iter().enumerate() {
+                for &j in items[n + 1..]
+                    .iter()
+                    .filter(|&&j| index.is_first_shared_band(i, j, band))
+                {
+                    assert!(candidates.insert((i.min(j), i.max(j))), "({}, {}) reported twice", i, j);
+                }
+            }
+        }
+        candidates
+    }
+
+    #[test]
+    fn band_keys() {
+        let bytes = [0b1010_0000, 0b0000_0001];
+        assert_eq!(band_key(&bytes, 0, 8), 0b1010_0000);
+        assert_eq!(band_key(&bytes, 5, 9), 0b1101);
+        assert_eq!(band_key(&bytes, 9, 16), 0);
+    }
+
+    #[test]
+    fn full_recall_with_threshold_plus_one_bands() {
+        for byte_count in [8, 18, 32] {
+            let img_hashes = random_hashes(200, byte_count, 0x2545_f491_4f6c_dd1d + byte_count as u64);
+            for threshold in [0, 2, 5, 9] {
+                let index = BandIndex::new(&img_hashes, threshold as usize + 1);
+                let candidates = candidate_pairs(&index);
+                let mut expected_count = 0;
+                for (i, j) in (0..img_hashes.len()).tuple_combinations() {
+                    if img_hashes[i].1.dist(&img_hashes[j].1) <= threshold {
+                        expected_count += 1;
+                        assert!(
+                            candidates.contains(&(i, j)),
+                            "pair ({}, {}) missed with {} bytes and threshold {}",
+                            i,
+                            j,
+                            byte_count,
+                            threshold
+                        );
+                    }
+                }
+                assert!(expected_count > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn band_count_limits() {
+        let img_hashes = random_hashes(10, 32, 1);
+        // no band may be wider than 128 bits
+        assert_eq!(BandIndex::new(&img_hashes, 1).band_count(), 2);
+        // no band may be narrower than 1 bit
+        assert_eq!(BandIndex::new(&img_hashes, 1000).band_count(), 256);
+        assert_eq!(BandIndex::new(&[], 5).band_count(), 1);
+    }
+}
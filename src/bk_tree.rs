@@ -0,0 +1,179 @@
+//! This module implements a BK-tree, a metric tree over hamming distance
+//! that allows finding all hashes within a radius of a query hash
+//! without comparing against every hash in the collection.
+//!
+//! See https://en.wikipedia.org/wiki/BK-tree.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use img_hash::ImageHash;
+
+#[derive(Debug)]
+struct Node {
+    /// Index of the hash in the backing slice.
+    item: usize,
+    /// Child nodes, keyed by their distance to this node.
+    children: HashMap<u32, usize>,
+}
+
+/// A BK-tree built over a slice of image hashes.
+///
+/// Items are referred to by their index in the backing slice.
+#[derive(Debug)]
+pub struct BkTree<'a> {
+    img_hashes: &'a [(PathBuf, ImageHash)],
+    nodes: Vec<Node>,
+}
+impl<'a> BkTree<'a> {
+    /// Build a tree containing all the hashes in the slice.
+    pub fn new(img_hashes: &'a [(PathBuf, ImageHash)]) -> Self {
+        let mut tree = Self {
+            img_hashes,
+            nodes: Vec::with_capacity(img_hashes.len()),
+        };
+        (0..img_hashes.len()).for_each(|item| tree.insert(item));
+        tree
+    }
+
+    fn hash_of(&self, item: usize) -> &ImageHash {
+        &self.img_hashes[item].1
+    }
+
+    fn insert(&mut self, item: usize) {
+        let new_node_idx = self.nodes.len();
+        self.nodes.push(Node {
+            item,
+            children: HashMap::new(),
+        });
+        if new_node_idx == 0 {
+            return; // root
+        }
+
+        // walk down until finding a vacant slot
+        let mut current = 0;
+        loop {
+            let dist = self.hash_of(self.nodes[current].item).dist(self.hash_of(item));
+            match self.nodes[current].children.get(&dist) {
+                Some(&child) => current = child,
+                None => {
+                    self.nodes[current].children.insert(dist, new_node_idx);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Find all items whose distance to the query hash is within the radius (inclusive).
+    ///
+    /// Returns a list of items and their distances, in no particular order,
+    /// and the number of distances computed.
+    pub fn find_within(&self, query: &ImageHash, radius: u32) -> (Vec<(usize, u32)>, usize) {
+        let mut found = vec![];
+        if self.nodes.is_empty() {
+            return (found, 0);
+        }
+
+        // by triangle inequality, only children whose edge distance is within
+        // [dist - radius, dist + radius] can contain matches
+        let mut stack = vec![0];
+        let mut dist_count = 0;
+        while let Some(current) = stack.pop() {
+            dist_count += 1;
+            let node = &self.nodes[current];
+            let dist = self.hash_of(node.item).dist(query);
+            if dist <= radius {
+                found.push((node.item, dist));
+            }
+            let lower = dist.saturating_sub(radius);
+            let upper = dist.saturating_add(radius);
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(&edge, _)| lower <= edge && edge <= upper)
+                    .map(|(_, &child)| child),
+            );
+        }
+        (found, dist_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+
+    /// Random hashes of 64 bits, clustered around a few centers so that
+    /// small radii find some matches. Uses xorshift, for reproducibility.
+    fn random_hashes(count: usize, seed: u64) -> Vec<(PathBuf, ImageHash)> {
+        let mut state = seed;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let centers: Vec<_> = (0..4).map(|_| next()).collect();
+        (0..count)
+            .map(|n| {
+                // flip a few random bits of a center
+                let flips = (0..next() % 12).fold(0, |flips, _| flips | 1 << (next() % 64));
+                let bits = centers[n % centers.len()] ^ flips;
+                let hash = ImageHash::from_bytes(&bits.to_le_bytes()).unwrap();
+                (PathBuf::from(format!("{}.jpg", n)), hash)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let img_hashes = random_hashes(300, 0x9e37_79b9_7f4a_7c15);
+        let tree = BkTree::new(&img_hashes);
+        for radius in [0, 1, 4, 8, 16, 64] {
+            for (query, (_, hash)) in img_hashes.iter().enumerate().step_by(7) {
+                let (mut found, dist_count) = tree.find_within(hash, radius);
+                found.sort_unstable();
+                let expected: Vec<_> = img_hashes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, other))| (i, hash.dist(other)))
+                    .filter(|&(_, dist)| dist <= radius)
+                    .collect();
+                assert_eq!(found, expected, "query {}, radius {}", query, radius);
+                assert!(dist_count <= img_hashes.len());
+            }
+        }
+    }
+
+    #[test]
+    fn radius_is_inclusive() {
+        let img_hashes: Vec<_> = [0u64, 0b1, 0b111, 0b1111]
+            .iter()
+            .enumerate()
+            .map(|(n, bits)| {
+                (
+                    PathBuf::from(n.to_string()),
+                    ImageHash::from_bytes(&bits.to_le_bytes()).unwrap(),
+                )
+            })
+            .collect();
+        let tree = BkTree::new(&img_hashes);
+        let found_items = |radius| {
+            let (found, _) = tree.find_within(&img_hashes[0].1, radius);
+            found.into_iter().sorted().collect::<Vec<_>>()
+        };
+
+        assert_eq!(found_items(0), vec![(0, 0)]);
+        assert_eq!(found_items(2), vec![(0, 0), (1, 1)]);
+        assert_eq!(found_items(3), vec![(0, 0), (1, 1), (2, 3)]);
+        assert_eq!(found_items(4), vec![(0, 0), (1, 1), (2, 3), (3, 4)]);
+    }
+
+    #[test]
+    fn empty_tree() {
+        let tree = BkTree::new(&[]);
+        assert_eq!(
+            tree.find_within(&ImageHash::from_bytes(&[0; 8]).unwrap(), 64),
+            (vec![], 0)
+        );
+    }
+}
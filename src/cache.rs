@@ -0,0 +1,272 @@
+//! This module implements the persistent on-disk cache of image hashes,
+//! which allows subsequent runs to skip decoding and hashing
+//! files that have not changed since.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    time::UNIX_EPOCH,
+};
+
+use img_hash::ImageHash;
+use serde::{Deserialize, Serialize};
+
+/// Bump this whenever the on-disk format changes incompatibly.
+/// Cache files with a different version are discarded.
+const CACHE_VERSION: u32 = 1;
+
+/// The file size and modification time of a file at the time it was hashed.
+/// A cache entry is only valid if the file still has the same stamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+}
+impl FileStamp {
+    /// Read the stamp of a file. Returns `None` if it cannot be determined.
+    fn of(path: &Path) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            size: meta.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+/// A hash is only reusable if it was computed with identical settings.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct CacheKey {
+    /// Canonical path of the file.
+    path: PathBuf,
+    /// Name of the algorithm, as specified on the CLI.
+    algorithm: String,
+    hash_size: (u32, u32),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    #[serde(flatten)]
+    key: CacheKey,
+    #[serde(flatten)]
+    stamp: FileStamp,
+    /// The hash, encoded in base64.
+    hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    entries: Vec<CacheEntry>,
+}
+
+/// An on-disk cache of image hashes, bound to a single set of hash settings.
+///
+/// Entries for other hash settings are retained as-is,
+/// so that switching between settings does not invalidate the cache.
+#[derive(Debug)]
+pub struct HashCache {
+    file_path: PathBuf,
+    algorithm: String,
+    hash_size: (u32, u32),
+    entries: HashMap<CacheKey, (FileStamp, String)>,
+    /// Files that missed the cache, indexed by their path as loaded,
+    /// waiting for their hashes to be computed.
+    pending: HashMap<PathBuf, (CacheKey, FileStamp)>,
+    hit_count: usize,
+}
+impl HashCache {
+    /// Load the cache from a file. A missing file results in an empty cache,
+    /// and so does a corrupt one, with a warning, so that it gets replaced on save.
+    ///
+    /// If `rebuild` is set, the file is not read at all, so that all files are hashed again.
+    ///
+    /// Returns Err if the file exists but cannot be opened.
+    pub fn load(file_path: &Path, algorithm: &str, hash_size: (u32, u32), rebuild: bool) -> Result<Self, String> {
+        let mut entries = HashMap::new();
+        if !rebuild && file_path.exists() {
+            let file = File::open(file_path).map_err(|e| e.to_string())?;
+            match serde_json::from_reader::<_, CacheFile>(BufReader::new(file)) {
+                Ok(cache_file) if cache_file.version == CACHE_VERSION => {
+                    entries = cache_file
+                        .entries
+                        .into_iter()
+                        .map(|CacheEntry { key, stamp, hash }| (key, (stamp, hash)))
+                        .collect();
+                }
+                Ok(cache_file) => eprintln!(
+                    "Hash cache has an incompatible version {} (expected {}); it will be rebuilt",
+                    cache_file.version, CACHE_VERSION
+                ),
+                Err(e) => eprintln!("Hash cache is corrupt ({}); it will be rebuilt", e),
+            }
+        }
+
+        Ok(Self {
+            file_path: file_path.to_path_buf(),
+            algorithm: algorithm.to_string(),
+            hash_size,
+            entries,
+            pending: HashMap::new(),
+            hit_count: 0,
+        })
+    }
+
+    /// Look up the hash of a file.
+    ///
+    /// Returns `None` if there is no entry, or if the file has been modified
+    /// since. In that case, the file is remembered as pending, so that
+    /// its hash can be added later via [`HashCache::update`].
+    pub fn lookup(&mut self, path: &Path) -> Option<ImageHash> {
+        let canonical_path = path.canonicalize().ok()?;
+        let stamp = FileStamp::of(&canonical_path)?;
+        let key = CacheKey {
+            path: canonical_path,
+            algorithm: self.algorithm.clone(),
+            hash_size: self.hash_size,
+        };
+
+        let cached_hash = self
+            .entries
+            .get(&key)
+            .filter(|(cached_stamp, _)| *cached_stamp == stamp)
+            .and_then(|(_, hash)| ImageHash::from_base64(hash).ok());
+        match cached_hash {
+            Some(_) => self.hit_count += 1,
+            None => {
+                self.pending.insert(path.to_path_buf(), (key, stamp));
+            }
+        }
+        cached_hash
+    }
+
+    /// Add the newly computed hash of a file that previously missed the cache.
+    ///
+    /// Does nothing if the file was not pending.
+    pub fn update(&mut self, path: &Path, hash: &ImageHash) {
+        if let Some((key, stamp)) = self.pending.remove(path) {
+            self.entries.insert(key, (stamp, hash.to_base64()));
+        }
+    }
+
+    /// The number of successful lookups so far.
+    pub fn hit_count(&self) -> usize {
+        self.hit_count
+    }
+
+    /// The path of the backing cache file.
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// Write the cache back to its file.
+    /// Entries of files that no longer exist are dropped.
+    ///
+    /// The cache is first written to a temporary file,
+    /// which then replaces the original, so that it is never left corrupt.
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.file_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+
+        let cache_file = CacheFile {
+            version: CACHE_VERSION,
+            entries: self
+                .entries
+                .iter()
+                .filter(|(key, _)| key.path.exists())
+                .map(|(key, (stamp, hash))| CacheEntry {
+                    key: key.clone(),
+                    stamp: *stamp,
+                    hash: hash.clone(),
+                })
+                .collect(),
+        };
+
+        // unique to the process, so that concurrent runs do not write to the same file
+        let mut tmp_path = self.file_path.clone().into_os_string();
+        tmp_path.push(format!(".{}.tmp", process::id()));
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut writer = BufWriter::new(File::create(&tmp_path).map_err(|e| e.to_string())?);
+        serde_json::to_writer(&mut writer, &cache_file).map_err(|e| e.to_string())?;
+        writer.flush().map_err(|e| e.to_string())?;
+        fs::rename(&tmp_path, &self.file_path).map_err(|e| e.to_string())
+    }
+}
+
+/// The default location of the cache file, following the XDG base directory
+/// specification: `$XDG_CACHE_HOME/img_dedup/hashes.json`,
+/// falling back to `~/.cache/img_dedup/hashes.json`.
+pub fn default_cache_path() -> PathBuf {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(|| PathBuf::from(".cache"));
+    cache_home.join("img_dedup").join("hashes.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    /// Load a cache, look up a file (which misses), and add its hash.
+    fn cache_with_entry(cache_path: &Path, img_path: &Path, rebuild: bool) -> HashCache {
+        let mut cache = HashCache::load(cache_path, "gradient", (8, 8), rebuild).unwrap();
+        assert!(cache.lookup(img_path).is_none());
+        cache.update(img_path, &ImageHash::from_bytes(&[0x5a; 8]).unwrap());
+        cache
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = test_dir("cache-round-trip");
+        let (cache_path, img_path) = (dir.join("hashes.json"), dir.join("a.jpg"));
+        fs::write(&img_path, "a").unwrap();
+        cache_with_entry(&cache_path, &img_path, false).save().unwrap();
+
+        let mut cache = HashCache::load(&cache_path, "gradient", (8, 8), false).unwrap();
+        assert_eq!(cache.lookup(&img_path), ImageHash::from_bytes(&[0x5a; 8]).ok());
+        assert_eq!(cache.hit_count(), 1);
+
+        // other settings miss the cache
+        let mut cache = HashCache::load(&cache_path, "mean", (8, 8), false).unwrap();
+        assert!(cache.lookup(&img_path).is_none());
+
+        // rebuilding ignores the saved entries
+        let mut cache = HashCache::load(&cache_path, "gradient", (8, 8), true).unwrap();
+        assert!(cache.lookup(&img_path).is_none());
+    }
+
+    #[test]
+    fn corrupt_file_starts_empty() {
+        let dir = test_dir("cache-corrupt");
+        let (cache_path, img_path) = (dir.join("hashes.json"), dir.join("a.jpg"));
+        fs::write(&img_path, "a").unwrap();
+        fs::write(&cache_path, "{\"version\": 1, \"entr").unwrap();
+
+        // the corrupt file is replaced on save
+        cache_with_entry(&cache_path, &img_path, false).save().unwrap();
+        let mut cache = HashCache::load(&cache_path, "gradient", (8, 8), false).unwrap();
+        assert!(cache.lookup(&img_path).is_some());
+    }
+
+    #[test]
+    fn save_drops_missing_files() {
+        let dir = test_dir("cache-missing");
+        let (cache_path, img_path) = (dir.join("hashes.json"), dir.join("a.jpg"));
+        fs::write(&img_path, "a").unwrap();
+        let cache = cache_with_entry(&cache_path, &img_path, false);
+        fs::remove_file(&img_path).unwrap();
+        cache.save().unwrap();
+
+        let cache = HashCache::load(&cache_path, "gradient", (8, 8), false).unwrap();
+        assert!(cache.entries.is_empty());
+    }
+}
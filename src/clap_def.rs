@@ -3,7 +3,13 @@
 use clap::{crate_version, App, AppSettings, Arg, SubCommand};
 use regex::Regex;
 
-use crate::cli_helper::parse_hash_size;
+use crate::{
+    cache::default_cache_path,
+    cli_helper::{
+        parse_band_count, parse_hash_size, parse_image_format, parse_keep_rules_file, parse_preview_size,
+        parse_template,
+    },
+};
 
 /// Build a clap app. Only call once.
 pub fn build_app() -> App<'static, 'static> {
@@ -42,8 +48,178 @@ pub fn build_app() -> App<'static, 'static> {
             "The minimum hamming distance for images to be considered similar (inclusive)\
             \nNote: the larger the hash size, the larger the hamming distances will generally become",
         );
+    let arg_search_mode = Arg::with_name("search-mode")
+        .short("m")
+        .long("search-mode")
+        .takes_value(true)
+        .possible_values(&["exact", "banded"])
+        .default_value("exact")
+        .help("Set the strategy used to search for similar pairs (long help available)")
+        .long_help(
+            "Set the strategy used to search for similar pairs\
+            \n- exact: find every pair within the threshold using a BK-tree index\
+            \n- banded: split each hash into bands, and only compare images sharing an identical band \
+            (multi-index hashing); faster on huge collections, but may miss pairs if there are too few bands",
+        );
+    let arg_bands = Arg::with_name("bands")
+        .short("b")
+        .long("bands")
+        .takes_value(true)
+        .validator(|arg| parse_band_count(&arg).map(|_| ()))
+        .help("Set the number of bands for the banded search mode (long help available)")
+        .long_help(
+            "Set the number of bands for the banded search mode\
+            \nDefaults to threshold + 1, which guarantees that no similar pair is missed\
+            \nFewer bands are faster, but may miss pairs; more bands examine more candidates",
+        );
+    let arg_linkage = Arg::with_name("linkage")
+        .short("l")
+        .long("linkage")
+        .takes_value(true)
+        .possible_values(&["single", "complete", "star"])
+        .default_value("single")
+        .help("Set the criterion for grouping similar images (long help available)")
+        .long_help(
+            "Set the criterion for grouping similar images\
+            \n- single: any chain of similar pairs forms a group, even if its ends are far apart\
+            \n- complete: every member is within the threshold of every other member\
+            \n- star: every member is within the threshold of a central representative",
+        );
+    let arg_on_collision = Arg::with_name("on-collision")
+        .long("on-collision")
+        .takes_value(true)
+        .possible_values(&["suffix", "hash", "skip", "abort", "overwrite"])
+        .default_value("suffix")
+        .help("Set what to do if a file with the same name exists at the destination (long help available)")
+        .long_help(
+            "Set what to do if a file with the same name exists at the destination\
+            \n- suffix: append a numeric suffix, e.g. IMG_0001-1.jpg\
+            \n- hash: append a short checksum of the file's content, e.g. IMG_0001-3fa2b81c.jpg\
+            \n- skip: leave the file where it is\
+            \n- abort: stop processing any further files\
+            \n- overwrite: replace the existing file (the only option that may lose data); \
+            files moved earlier in the same run are never replaced",
+        );
+    let arg_format = Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .possible_values(&["text", "json", "ndjson", "csv", "tsv"])
+        .default_value("text")
+        .help("Set the format of the results written to stdout (long help available)")
+        .long_help(
+            "Set the format of the results written to stdout\
+            \n- text: human-readable, with shortened paths\
+            \n- json: a single document, with the hash settings and an array of records\
+            \n- ndjson: one JSON record per line, each including the hash settings\
+            \n- csv, tsv: a table with a header row, for spreadsheets\
+            \nMachine-readable formats use absolute paths and base64 hashes, \
+            and include the size and dimensions of each image\
+            \nPaths that are not valid UTF-8 are never written; the output fails instead\
+            \nProgress messages are always written to stderr",
+        );
+    let arg_scan_format = arg_format.clone().possible_values(&["fdupes", "rmlint"]).long_help(
+        "Set the format of the results written to stdout\
+            \n- text: human-readable, with shortened paths\
+            \n- json: a single document, with the hash settings and an array of records\
+            \n- ndjson: one JSON record per line, each including the hash settings\
+            \n- csv, tsv: a table with a header row, for spreadsheets\
+            \n- fdupes: one path per line, with a blank line after each group; the keeper is listed first\
+            \n- rmlint: rmlint's JSON array, with the keeper of each group as the original\
+            \nMachine-readable formats use absolute paths and base64 hashes, \
+            and include the size and dimensions of each image\
+            \nPaths that are not valid UTF-8 are never written; the output fails instead\
+            \nThe fdupes and rmlint formats require --view groups, and use the keeper options to choose the keepers\
+            \nProgress messages are always written to stderr",
+    );
+    let arg_template = Arg::with_name("template")
+        .long("template")
+        .takes_value(true)
+        .validator(|arg| parse_template(&arg).map(|_| ()))
+        .help("Render each result with a template, e.g. '{path0}\\t{path1}\\t{dist}' (long help available)")
+        .long_help(
+            "Render each result with a template, overriding --format\
+            \nThe template is rendered once per line; placeholders are the fields of the JSON output, e.g.:\
+            \n  hash: {path} {name} {root} {size} {width} {height} {hash}\
+            \n  scan-duplicates --view pairs: {path0} {name0} {root0} {hash0} ... {path1} {name1} {root1} ... {dist}\
+            \n  scan-duplicates --view groups: {group} {path} {name} {root} {hash} {dist} {representative} ...\
+            \n  all: {algorithm} {hash_size}\
+            \nThe escapes \\t, \\n and \\\\ are supported; write literal braces as {{ and }}",
+        );
+    let arg_dry_run = Arg::with_name("dry-run")
+        .short("n")
+        .long("dry-run")
+        .help("Only print what would be done, without modifying any file");
+    let arg_keep = Arg::with_name("keep")
+        .short("k")
+        .long("keep")
+        .takes_value(true)
+        .possible_values(&[
+            "highest-resolution",
+            "largest-file",
+            "oldest",
+            "shortest-path",
+            "preferred-format",
+        ])
+        .default_value("highest-resolution")
+        .help("Set the policy for choosing the original to keep in each group (long help available)")
+        .long_help(
+            "Set the policy for choosing the original to keep in each group\
+            \nAll other members of the group are treated as duplicates\
+            \nRemaining ties are broken by choosing the lexicographically smallest path",
+        );
+    let arg_preferred_format = Arg::with_name("preferred-format")
+        .long("preferred-format")
+        .takes_value(true)
+        .required_if("keep", "preferred-format")
+        .validator(|arg| parse_image_format(&arg).map(|_| ()))
+        .help("The format to prefer with the preferred-format policy, as a file extension (e.g. png)");
+    let arg_keep_rules = Arg::with_name("keep-rules")
+        .long("keep-rules")
+        .takes_value(true)
+        .validator(|arg| parse_keep_rules_file(&arg).map(|_| ()))
+        .help("Load an ordered list of keeper rules from a file (long help available)")
+        .long_help(
+            "Load an ordered list of keeper rules from a file, overriding --keep\
+            \nOne rule per line; later rules only break ties left by earlier ones\
+            \nEmpty lines and lines starting with '#' are ignored\
+            \nAvailable rules:\
+            \n  highest-resolution | largest-file | oldest | shortest-path\
+            \n  preferred-format <ext> | prefer-lossless | prefer-exif\
+            \n  prefer-path <regex> | avoid-path <regex>  (matched against the absolute path)\
+            \n  prefer-name <regex> | avoid-name <regex>  (matched against the file name)",
+        );
+    let arg_journal = Arg::with_name("journal")
+        .long("journal")
+        .takes_value(true)
+        .help("The file to record moves in, for the undo subcommand (long help available)")
+        .long_help(
+            "The file to record moves in, for the undo subcommand\
+            \nDefaults to img_dedup-journal.jsonl in the destination directory\
+            \nIf the file exists, new moves are appended to it",
+        );
+    let arg_permanent = Arg::with_name("permanent")
+        .long("permanent")
+        .help("Delete duplicates permanently instead of moving them to the trash (long help available)")
+        .long_help(
+            "Delete duplicates permanently instead of moving them to the trash\
+            \nBy default, duplicates are moved to the trash of the current user \
+            ($XDG_DATA_HOME/Trash), from where they can be restored with a file manager\
+            \nPermanently deleted files cannot be recovered",
+        );
+    let arg_link_type = Arg::with_name("link-type")
+        .long("link-type")
+        .takes_value(true)
+        .possible_values(&["hard", "symbolic", "auto"])
+        .default_value("auto")
+        .help("Set the kind of link to replace duplicates with (long help available)")
+        .long_help(
+            "Set the kind of link to replace duplicates with\
+            \n- hard: a hard link; fails if the original is on another filesystem\
+            \n- symbolic: a symbolic link, relative to the duplicate's directory\
+            \n- auto: a hard link where possible, otherwise a symbolic link",
+        );
 
-    App::new("Image Deduplicator")
+    let app = App::new("Image Deduplicator")
         .version(crate_version!())
         .author("Scheimong <28627918+cyqsimon@users.noreply.github.com>")
         .about("A command line program that finds and removes duplicated images using perceptual hashing")
@@ -55,10 +231,16 @@ pub fn build_app() -> App<'static, 'static> {
             AppSettings::SubcommandRequiredElseHelp,
         ])
         .arg(
-            Arg::with_name("input_dir")
-                .required(true)
+            Arg::with_name("inputs")
+                .multiple(true)
                 .index(1)
-                .help("The directory to source input images from"),
+                .help("The directories and/or individual files to source input images from (long help available)")
+                .long_help(
+                    "The directories and/or individual files to source input images from\
+                    \nAll inputs are merged into a single scan\
+                    \nRequired by all subcommands that scan for images\
+                    \nThe regex filter does not apply to individual files",
+                ),
         )
         .arg(
             Arg::with_name("input_filter")
@@ -69,6 +251,51 @@ pub fn build_app() -> App<'static, 'static> {
                 .validator(|arg| Regex::new(&arg).map(|_| ()).map_err(|e| e.to_string()))
                 .help("Only accept files that match the regex filter"),
         )
+        .arg(
+            Arg::with_name("max_depth")
+                .short("d")
+                .long("max-depth")
+                .takes_value(true)
+                .validator(|arg| arg.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Limit how deep to descend into subdirectories (long help available)")
+                .long_help(
+                    "Limit how deep to descend into subdirectories\
+                    \nFiles directly inside the input directory are at depth 1\
+                    \nUnlimited if not specified",
+                ),
+        )
+        .arg(
+            Arg::with_name("follow_symlinks")
+                .short("L")
+                .long("follow-symlinks")
+                .help("Follow symbolic links instead of skipping them (loops are detected)"),
+        )
+        .arg({
+            // create never-freed static str, for the same reason as `concurrency` below
+            let default_val: &'static str =
+                Box::leak(default_cache_path().to_string_lossy().into_owned().into_boxed_str());
+            Arg::with_name("cache")
+                .long("cache")
+                .takes_value(true)
+                .default_value(default_val)
+                .help("The file to cache computed hashes in (long help available)")
+                .long_help(
+                    "The file to cache computed hashes in\
+                    \nFiles whose path, size, modification time and hash settings match a cache entry \
+                    are not decoded and hashed again",
+                )
+        })
+        .arg(
+            Arg::with_name("no_cache")
+                .long("no-cache")
+                .conflicts_with("rebuild_cache")
+                .help("Neither read from nor write to the hash cache"),
+        )
+        .arg(
+            Arg::with_name("rebuild_cache")
+                .long("rebuild-cache")
+                .help("Discard the existing hash cache (e.g. if it is corrupt), and hash all files again"),
+        )
         .arg({
             // by default, use as many threads as the host has logical cores
             // create never-freed static str, see https://stackoverflow.com/a/30527289/5637701
@@ -82,7 +309,7 @@ pub fn build_app() -> App<'static, 'static> {
                 .validator(|arg| {
                     arg.parse::<usize>()
                         .map_err(|e| e.to_string())
-                        .and_then(|th| (th != 0).then(|| ()).ok_or("Cannot specify 0 threads".into()))
+                        .and_then(|th| (th != 0).then_some(()).ok_or("Cannot specify 0 threads".into()))
                 })
                 .help("The number of threads to use for parallel computing")
         })
@@ -97,14 +324,104 @@ pub fn build_app() -> App<'static, 'static> {
             SubCommand::with_name("hash")
                 .about("Compute and show hashes for the input files")
                 .arg(&arg_algo)
-                .arg(&arg_hash_size),
+                .arg(&arg_hash_size)
+                .arg(&arg_format)
+                .arg(&arg_template),
         )
         .subcommand(
             SubCommand::with_name("scan-duplicates")
                 .about("Scan the input files for duplicates and show them")
                 .arg(&arg_algo)
                 .arg(&arg_hash_size)
-                .arg(&arg_dist_threshold),
+                .arg(&arg_dist_threshold)
+                .arg(&arg_search_mode)
+                .arg(&arg_bands)
+                .arg(
+                    Arg::with_name("view")
+                        .long("view")
+                        .takes_value(true)
+                        .possible_values(&["groups", "pairs"])
+                        .default_value("groups")
+                        .help("Show duplicates as groups of similar images, or as raw similar pairs"),
+                )
+                .arg(&arg_linkage)
+                .arg(&arg_scan_format)
+                .arg(&arg_template)
+                .arg(
+                    Arg::with_name("preview")
+                        .long("preview")
+                        .takes_value(true)
+                        .min_values(0)
+                        .require_equals(true)
+                        .possible_values(&["auto", "kitty", "sixel", "text"])
+                        .help("Show thumbnails of each pair or group in the terminal (long help available)")
+                        .long_help(
+                            "Show thumbnails of each pair or group in the terminal, with the text format\
+                            \n- auto: detect the protocol from the environment (the default if no value is given)\
+                            \n- kitty: the kitty graphics protocol (kitty, WezTerm, Ghostty, Konsole)\
+                            \n- sixel: sixel graphics (xterm -ti vt340, foot, mlterm, Windows Terminal)\
+                            \n- text: colored half blocks, for terminals supporting neither\
+                            \nThumbnails are made while hashing, and kept in memory until the results are shown",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("preview-size")
+                        .long("preview-size")
+                        .takes_value(true)
+                        .default_value("128")
+                        .validator(|arg| parse_preview_size(&arg).map(|_| ()))
+                        .help("Set the maximum width and height of preview thumbnails, in pixels"),
+                )
+                .arg(
+                    Arg::with_name("plan")
+                        .long("plan")
+                        .takes_value(true)
+                        .help("Also write an editable plan of actions to a file (long help available)")
+                        .long_help(
+                            "Also write an editable plan of actions to a file, for the apply subcommand\
+                            \nThe plan lists each group, its keeper, and an action for every other member, \
+                            which can be changed before applying it",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("report")
+                        .long("report")
+                        .takes_value(true)
+                        .help("Also write an HTML report with thumbnails of each group (long help available)")
+                        .long_help(
+                            "Also write a self-contained HTML report with thumbnails of each group\
+                            \nEach image is shown with its path, dimensions and file size, \
+                            followed by the distances between all images of its group",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("script")
+                        .long("script")
+                        .takes_value(true)
+                        .help("Also write a shell script performing the action on each duplicate (long help available)")
+                        .long_help(
+                            "Also write a POSIX shell script performing the action on each duplicate\
+                            \nEach command is annotated with its group, keeper and distance, for review before running it",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("action")
+                        .long("action")
+                        .takes_value(true)
+                        .possible_values(&["move", "delete", "link", "keep"])
+                        .default_value("move")
+                        .help("The action for each non-keeper in a plan or script"),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .long("destination")
+                        .takes_value(true)
+                        .help("The destination directory for the move action in a script"),
+                )
+                .arg(&arg_link_type)
+                .arg(&arg_keep)
+                .arg(&arg_preferred_format)
+                .arg(&arg_keep_rules),
         )
         .subcommand(
             SubCommand::with_name("move-duplicates")
@@ -112,6 +429,15 @@ pub fn build_app() -> App<'static, 'static> {
                 .arg(&arg_algo)
                 .arg(&arg_hash_size)
                 .arg(&arg_dist_threshold)
+                .arg(&arg_search_mode)
+                .arg(&arg_bands)
+                .arg(&arg_linkage)
+                .arg(&arg_keep)
+                .arg(&arg_preferred_format)
+                .arg(&arg_keep_rules)
+                .arg(&arg_on_collision)
+                .arg(&arg_dry_run)
+                .arg(&arg_journal)
                 .arg(
                     Arg::with_name("destination")
                         .required(true)
@@ -119,4 +445,118 @@ pub fn build_app() -> App<'static, 'static> {
                         .help("The destination directory for duplicate files"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("delete-duplicates")
+                .about("Scan for duplicates, then move them to the trash or delete them")
+                .arg(&arg_algo)
+                .arg(&arg_hash_size)
+                .arg(&arg_dist_threshold)
+                .arg(&arg_search_mode)
+                .arg(&arg_bands)
+                .arg(&arg_linkage)
+                .arg(&arg_keep)
+                .arg(&arg_preferred_format)
+                .arg(&arg_keep_rules)
+                .arg(&arg_dry_run)
+                .arg(&arg_permanent),
+        )
+        .subcommand(
+            SubCommand::with_name("link-duplicates")
+                .about("Scan for duplicates, then replace those identical to the original kept in each group with links to it")
+                .arg(&arg_algo)
+                .arg(&arg_hash_size)
+                .arg(&arg_dist_threshold)
+                .arg(&arg_search_mode)
+                .arg(&arg_bands)
+                .arg(&arg_linkage)
+                .arg(&arg_keep)
+                .arg(&arg_preferred_format)
+                .arg(&arg_keep_rules)
+                .arg(&arg_dry_run)
+                .arg(&arg_link_type),
+        )
+        .subcommand(
+            SubCommand::with_name("review")
+                .about("Scan for duplicates, then review them interactively before acting (long help available)")
+                .long_about(
+                    "Scan for duplicates, then review them interactively before acting\
+                    \nEach group is shown with the dimensions, size, modification time, EXIF date \
+                    and distance of its images, which can each be marked to keep, move, delete, \
+                    or leave alone as not a duplicate\
+                    \nThe keeper options only choose the initially kept image; other images are initially marked \
+                    to move if a destination is given, or to delete otherwise",
+                )
+                .arg(&arg_algo)
+                .arg(&arg_hash_size)
+                .arg(&arg_dist_threshold)
+                .arg(&arg_search_mode)
+                .arg(&arg_bands)
+                .arg(&arg_linkage)
+                .arg(&arg_keep)
+                .arg(&arg_preferred_format)
+                .arg(&arg_keep_rules)
+                .arg(
+                    Arg::with_name("destination")
+                        .long("destination")
+                        .takes_value(true)
+                        .help("The destination directory for images marked to move"),
+                )
+                .arg(&arg_on_collision)
+                .arg(&arg_journal)
+                .arg(&arg_permanent)
+                .arg(&arg_dry_run),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Apply a plan written by scan-duplicates --plan, after checking that no file has changed")
+                .arg(
+                    Arg::with_name("destination")
+                        .long("destination")
+                        .takes_value(true)
+                        .help("The destination directory for files with the move action"),
+                )
+                .arg(&arg_on_collision)
+                .arg(&arg_journal)
+                .arg(&arg_permanent)
+                .arg(&arg_link_type)
+                .arg(&arg_dry_run)
+                .arg(
+                    Arg::with_name("plan")
+                        .required(true)
+                        .index(1)
+                        .help("The plan written by scan-duplicates"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("undo")
+                .about("Move files recorded in a journal back to their original paths")
+                .arg(&arg_dry_run)
+                .arg(
+                    Arg::with_name("journal")
+                        .required(true)
+                        .index(1)
+                        .help("The journal written by move-duplicates"),
+                ),
+        );
+
+    // extent sharing relies on a Linux-specific ioctl
+    #[cfg(target_os = "linux")]
+    let app = app.subcommand(
+        SubCommand::with_name("reflink-duplicates")
+            .about("Scan for duplicates, then make byte-identical ones share storage on disk")
+            .long_about(
+                "Scan for duplicates, then make byte-identical ones share storage on disk\
+                \nOnly supported on copy-on-write filesystems such as Btrfs and XFS\
+                \nAll files are left in place; visually similar but non-identical files are not affected",
+            )
+            .arg(&arg_algo)
+            .arg(&arg_hash_size)
+            .arg(&arg_dist_threshold)
+            .arg(&arg_search_mode)
+            .arg(&arg_bands)
+            .arg(&arg_linkage)
+            .arg(&arg_dry_run),
+    );
+
+    app
 }
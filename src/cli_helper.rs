@@ -1,7 +1,19 @@
 //! This module contains functions that help with parsing and validation
 //! of complex arguments provided by the user via CLI.
 
+use image::ImageFormat;
 use img_hash::HashAlg;
+use regex::Regex;
+
+use crate::{
+    cluster::Linkage,
+    compute::SearchMode,
+    io::{CollisionStrategy, LinkType},
+    keeper::KeepRule,
+    output::{OutputFormat, Template, TemplatePart},
+    plan::PlanAction,
+    preview::PreviewProtocol,
+};
 
 /// The `hash-size` argument can be provided in two ways:
 /// - either a single u32 (e.g. `24`, equivalent to `24,24`),
@@ -13,7 +25,7 @@ pub fn parse_hash_size(arg: &str) -> Result<(u32, u32), String> {
     fn parse_u32_nonzero(num: &str) -> Result<u32, String> {
         match num.parse::<u32>() {
             Ok(0) => Err("Hash size cannot be 0".to_string()),
-            Err(e) => Err(format!("{}: \"{}\"", e.to_string(), num)),
+            Err(e) => Err(format!("{}: \"{}\"", e, num)),
             Ok(v) => Ok(v),
         }
     }
@@ -46,3 +58,342 @@ pub fn parse_algo(arg: &str) -> Result<HashAlg, String> {
         other => Err(format!("\"{}\" is not a supported hashing algorithm", other)),
     }
 }
+
+/// This function parses the name of the selected search mode
+/// into its corresponding enum variant.
+///
+/// For the banded mode, the number of bands defaults to `threshold + 1`,
+/// which is the smallest number that guarantees no similar pair is missed.
+pub fn parse_search_mode(arg: &str, bands: Option<&str>, threshold: u32) -> Result<SearchMode, String> {
+    match arg {
+        "exact" => Ok(SearchMode::Exact),
+        "banded" => {
+            let band_count = match bands {
+                Some(bands) => parse_band_count(bands)?,
+                None => threshold as usize + 1,
+            };
+            Ok(SearchMode::Banded(band_count))
+        }
+        other => Err(format!("\"{}\" is not a supported search mode", other)),
+    }
+}
+
+/// The `bands` argument must be a non-zero usize.
+pub fn parse_band_count(arg: &str) -> Result<usize, String> {
+    match arg.parse::<usize>() {
+        Ok(0) => Err("Band count cannot be 0".to_string()),
+        Err(e) => Err(format!("{}: \"{}\"", e, arg)),
+        Ok(v) => Ok(v),
+    }
+}
+
+/// This function parses the name of the selected linkage
+/// into its corresponding enum variant.
+pub fn parse_linkage(arg: &str) -> Result<Linkage, String> {
+    match arg {
+        "single" => Ok(Linkage::Single),
+        "complete" => Ok(Linkage::Complete),
+        "star" => Ok(Linkage::Star),
+        other => Err(format!("\"{}\" is not a supported linkage", other)),
+    }
+}
+
+/// This function parses the name of the selected keeper policy
+/// into its corresponding rule.
+///
+/// The `preferred-format` policy additionally requires a format.
+pub fn parse_keep_rule(arg: &str, preferred_format: Option<&str>) -> Result<KeepRule, String> {
+    use KeepRule::*;
+    match arg {
+        "highest-resolution" => Ok(HighestResolution),
+        "largest-file" => Ok(LargestFile),
+        "oldest" => Ok(Oldest),
+        "shortest-path" => Ok(ShortestPath),
+        "preferred-format" => {
+            let format = preferred_format.ok_or("preferred-format requires a format")?;
+            parse_image_format(format).map(PreferFormat)
+        }
+        other => Err(format!("\"{}\" is not a supported keeper policy", other)),
+    }
+}
+
+/// A keeper rules file lists one rule per line, in order of priority.
+/// Rules are named after the keeper policies, with the following additions:
+/// - `preferred-format <ext>`
+/// - `prefer-lossless`
+/// - `prefer-exif`
+/// - `prefer-path <regex>` and `avoid-path <regex>`, matched against the absolute path
+/// - `prefer-name <regex>` and `avoid-name <regex>`, matched against the file name
+///
+/// Empty lines and lines starting with `#` are ignored.
+///
+/// This function reads and parses such a file.
+pub fn parse_keep_rules_file(path: &str) -> Result<Vec<KeepRule>, String> {
+    use KeepRule::*;
+
+    let content = std::fs::read_to_string(path).map_err(|e| format!("Cannot read \"{}\": {}", path, e))?;
+    let rules = content
+        .lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_num, line)| {
+            let (name, param) = match line.find(char::is_whitespace) {
+                Some(idx) => (&line[..idx], Some(line[idx..].trim())),
+                None => (line, None),
+            };
+            let parse_regex = || {
+                let param = param.ok_or_else(|| format!("{} requires a regex", name))?;
+                Regex::new(param).map_err(|e| e.to_string())
+            };
+            let rule = match name {
+                "prefer-lossless" => Ok(PreferLossless),
+                "prefer-exif" => Ok(PreferExif),
+                "prefer-path" => parse_regex().map(PreferPath),
+                "avoid-path" => parse_regex().map(AvoidPath),
+                "prefer-name" => parse_regex().map(PreferName),
+                "avoid-name" => parse_regex().map(AvoidName),
+                other => parse_keep_rule(other, param),
+            };
+            rule.map_err(|e| format!("Line {}: {}", line_num, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match rules.is_empty() {
+        true => Err(format!("\"{}\" contains no rules", path)),
+        false => Ok(rules),
+    }
+}
+
+/// This function parses an image format from its file extension (e.g. `png`).
+pub fn parse_image_format(arg: &str) -> Result<ImageFormat, String> {
+    ImageFormat::from_extension(arg.trim_start_matches('.'))
+        .ok_or_else(|| format!("\"{}\" is not a supported image format", arg))
+}
+
+/// This function parses the name of the selected collision strategy
+/// into its corresponding enum variant.
+pub fn parse_collision_strategy(arg: &str) -> Result<CollisionStrategy, String> {
+    use CollisionStrategy::*;
+    match arg {
+        "suffix" => Ok(Suffix),
+        "hash" => Ok(Hash),
+        "skip" => Ok(Skip),
+        "abort" => Ok(Abort),
+        "overwrite" => Ok(Overwrite),
+        other => Err(format!("\"{}\" is not a supported collision strategy", other)),
+    }
+}
+
+/// This function parses the name of the selected link type
+/// into its corresponding enum variant.
+pub fn parse_link_type(arg: &str) -> Result<LinkType, String> {
+    use LinkType::*;
+    match arg {
+        "hard" => Ok(Hard),
+        "symbolic" => Ok(Symbolic),
+        "auto" => Ok(Auto),
+        other => Err(format!("\"{}\" is not a supported link type", other)),
+    }
+}
+
+/// This function parses the name of the selected output format
+/// into its corresponding enum variant.
+pub fn parse_output_format(arg: &str) -> Result<OutputFormat, String> {
+    use OutputFormat::*;
+    match arg {
+        "text" => Ok(Text),
+        "json" => Ok(Json),
+        "ndjson" => Ok(Ndjson),
+        "csv" => Ok(Csv),
+        "tsv" => Ok(Tsv),
+        "fdupes" => Ok(Fdupes),
+        "rmlint" => Ok(Rmlint),
+        other => Err(format!("\"{}\" is not a supported output format", other)),
+    }
+}
+
+/// This function parses a user-defined output template,
+/// such as `{group}:{path}:{hash}`.
+///
+/// Placeholders are enclosed in braces. Literal braces are written as `{{` and `}}`,
+/// and the escape sequences `\t`, `\n` and `\\` are supported.
+/// Placeholder names are only checked when the template is rendered,
+/// as they depend on the output.
+pub fn parse_template(arg: &str) -> Result<Template, String> {
+    let mut parts = vec![];
+    let mut literal = String::new();
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('t') => literal.push('\t'),
+                Some('n') => literal.push('\n'),
+                Some('\\') => literal.push('\\'),
+                Some(other) => return Err(format!("Unsupported escape sequence \"\\{}\"", other)),
+                None => return Err("Template ends with an incomplete escape sequence".into()),
+            },
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest
+                    .find('}')
+                    .ok_or("Unclosed placeholder; use {{ for a literal brace")?;
+                let name = rest[..end].trim();
+                if name.is_empty() || name.contains('{') {
+                    return Err(format!("Invalid placeholder \"{{{}}}\"", &rest[..end]));
+                }
+                if !literal.is_empty() {
+                    parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(TemplatePart::Placeholder(name.to_string()));
+                chars = rest[end + 1..].chars();
+            }
+            '}' => return Err("Unmatched closing brace; use }} for a literal brace".into()),
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(TemplatePart::Literal(literal));
+    }
+    Ok(Template(parts))
+}
+
+/// This function parses the name of the selected plan action
+/// into its corresponding enum variant.
+pub fn parse_plan_action(arg: &str) -> Result<PlanAction, String> {
+    use PlanAction::*;
+    match arg {
+        "keep" => Ok(Keep),
+        "move" => Ok(Move),
+        "delete" => Ok(Delete),
+        "link" => Ok(Link),
+        other => Err(format!("\"{}\" is not a supported plan action", other)),
+    }
+}
+
+/// This function parses the name of the selected preview protocol
+/// into its corresponding enum variant.
+///
+/// Returns `None` for `auto`, in which case the protocol should be detected.
+pub fn parse_preview_protocol(arg: &str) -> Result<Option<PreviewProtocol>, String> {
+    use PreviewProtocol::*;
+    match arg {
+        "auto" => Ok(None),
+        "kitty" => Ok(Some(Kitty)),
+        "sixel" => Ok(Some(Sixel)),
+        "text" => Ok(Some(Text)),
+        other => Err(format!("\"{}\" is not a supported preview protocol", other)),
+    }
+}
+
+/// This function parses the maximum width and height of preview thumbnails, in pixels.
+pub fn parse_preview_size(arg: &str) -> Result<u32, String> {
+    match arg.parse::<u32>() {
+        Ok(size) if (8..=1024).contains(&size) => Ok(size),
+        Ok(_) => Err("Preview size must be between 8 and 1024 pixels".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        output::TemplatePart::{Literal, Placeholder},
+        test_util::test_dir,
+    };
+
+    fn parts(arg: &str) -> Vec<TemplatePart> {
+        parse_template(arg).unwrap().0
+    }
+
+    #[test]
+    fn template_placeholders() {
+        assert_eq!(
+            parts("{path0} - { path1 }: {dist}"),
+            vec![
+                Placeholder("path0".into()),
+                Literal(" - ".into()),
+                Placeholder("path1".into()),
+                Literal(": ".into()),
+                Placeholder("dist".into()),
+            ]
+        );
+        assert_eq!(parts(""), vec![]);
+    }
+
+    #[test]
+    fn template_escapes() {
+        assert_eq!(
+            parts(r"{path}\t{hash}\n\\"),
+            vec![
+                Placeholder("path".into()),
+                Literal("\t".into()),
+                Placeholder("hash".into()),
+                Literal("\n\\".into()),
+            ]
+        );
+        assert_eq!(
+            parts("{{{path}}}"),
+            vec![Literal("{".into()), Placeholder("path".into()), Literal("}".into())]
+        );
+        assert_eq!(parts("{{path}}"), vec![Literal("{path}".into())]);
+    }
+
+    #[test]
+    fn template_errors() {
+        assert!(parse_template(r"\x").is_err());
+        assert!(parse_template("trailing \\").is_err());
+        assert!(parse_template("{path").is_err());
+        assert!(parse_template("path}").is_err());
+        assert!(parse_template("{}").is_err());
+        assert!(parse_template("{ }").is_err());
+        assert!(parse_template("{a{b}").is_err());
+    }
+
+    /// Write a keeper rules file with the specified content, and parse it.
+    fn keep_rules(name: &str, content: &str) -> Result<Vec<KeepRule>, String> {
+        let path = test_dir(name).join("rules.txt");
+        std::fs::write(&path, content).unwrap();
+        parse_keep_rules_file(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn keep_rules_file() {
+        let rules = keep_rules(
+            "keep-rules",
+            "# prefer originals\n\n  avoid-path  /backup/ \nprefer-exif\n\tpreferred-format png\noldest\n",
+        )
+        .unwrap();
+        let descriptions: Vec<_> = rules.iter().map(KeepRule::describe).collect();
+        assert_eq!(
+            descriptions,
+            vec![
+                "path not matching //backup//",
+                "presence of EXIF metadata",
+                "preferred format Png",
+                "oldest modification time",
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_rules_file_errors() {
+        let err = keep_rules("keep-rules-unknown", "oldest\n# comment\nnewest\n").unwrap_err();
+        assert!(err.starts_with("Line 3: "), "{}", err);
+        let err = keep_rules("keep-rules-regex", "prefer-name\n").unwrap_err();
+        assert!(err.contains("requires a regex"), "{}", err);
+        assert!(keep_rules("keep-rules-bad-regex", "prefer-name (\n").is_err());
+        let err = keep_rules("keep-rules-empty", "\n  \n# only comments\n").unwrap_err();
+        assert!(err.ends_with("contains no rules"), "{}", err);
+        assert!(parse_keep_rules_file("/does/not/exist").is_err());
+    }
+}
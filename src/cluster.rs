@@ -0,0 +1,415 @@
+//! This module contains functions that turn similar pairs
+//! into groups of duplicate images.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use img_hash::ImageHash;
+use itertools::Itertools;
+
+/// A group of images that are considered duplicates of each other.
+#[derive(Debug, Clone)]
+pub struct DupGroup<'a> {
+    /// All members alongside their distances to the representative,
+    /// sorted by distance in ascending order.
+    /// The representative is always the first member.
+    pub members: Vec<(&'a Path, u32)>,
+    /// The hash of each member, in the same order as `members`.
+    pub hashes: Vec<&'a ImageHash>,
+}
+impl<'a> DupGroup<'a> {
+    /// The member that best represents the group.
+    pub fn representative(&self) -> &'a Path {
+        self.members[0].0
+    }
+
+    /// The distance between two members, by their indices in `members`.
+    pub fn dist(&self, i: usize, j: usize) -> u32 {
+        self.hashes[i].dist(self.hashes[j])
+    }
+
+    /// The largest distance between any two members.
+    ///
+    /// This compares all pairs of members, so it is only computed when asked for.
+    pub fn diameter(&self) -> u32 {
+        (0..self.hashes.len())
+            .tuple_combinations()
+            .map(|(i, j)| self.dist(i, j))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// A minimal union-find (disjoint-set) structure over item indices.
+struct UnionFind {
+    parents: Vec<usize>,
+}
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        Self {
+            parents: (0..len).collect(),
+        }
+    }
+    fn find(&mut self, item: usize) -> usize {
+        let mut root = item;
+        while self.parents[root] != root {
+            root = self.parents[root];
+        }
+        // path compression
+        let mut current = item;
+        while self.parents[current] != root {
+            let next = self.parents[current];
+            self.parents[current] = root;
+            current = next;
+        }
+        root
+    }
+    fn union(&mut self, a: usize, b: usize) {
+        let (root_a, root_b) = (self.find(a), self.find(b));
+        if root_a != root_b {
+            self.parents[root_b] = root_a;
+        }
+    }
+}
+
+/// The criterion that decides whether similar images belong to the same group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linkage {
+    /// Any chain of similar pairs links images into the same group,
+    /// even if the two ends of the chain are far apart.
+    Single,
+    /// Every member of a group is within the threshold of every other member.
+    Complete,
+    /// Every member of a group is within the threshold of a central representative.
+    Star,
+}
+
+/// This function groups images according to the selected linkage.
+///
+/// Only images that appear in at least one similar pair are grouped;
+/// singleton groups are never returned.
+///
+/// Groups are sorted by size in descending order,
+/// then by the path of their representatives.
+pub fn calc_groups<'a>(
+    img_hashes: &'a [(PathBuf, ImageHash)],
+    similar_pairs: &[(&Path, &Path, u32)],
+    linkage: Linkage,
+    threshold: u32,
+) -> Vec<DupGroup<'a>> {
+    let indices: HashMap<&Path, usize> = img_hashes
+        .iter()
+        .enumerate()
+        .map(|(i, (path, _))| (path.as_path(), i))
+        .collect();
+    let index_pairs: Vec<_> = similar_pairs
+        .iter()
+        .map(|(p0, p1, dist)| (indices[p0], indices[p1], *dist))
+        .collect();
+
+    let mut groups: Vec<_> = match linkage {
+        Linkage::Single => group_connected(&index_pairs)
+            .into_iter()
+            .map(|members| make_group(img_hashes, &members, None))
+            .collect(),
+        Linkage::Complete => group_complete(img_hashes, &index_pairs, threshold)
+            .into_iter()
+            .map(|members| make_group(img_hashes, &members, None))
+            .collect(),
+        Linkage::Star => group_star(img_hashes, &index_pairs)
+            .into_iter()
+            .map(|(center, members)| make_group(img_hashes, &members, Some(center)))
+            .collect(),
+    };
+    groups.retain(|g| g.members.len() > 1);
+    groups.sort_by(|g0, g1| {
+        g1.members
+            .len()
+            .cmp(&g0.members.len())
+            .then_with(|| g0.representative().cmp(g1.representative()))
+    });
+    groups
+}
+
+/// This function groups items into the connected components of
+/// the similarity graph, where each similar pair is an edge.
+fn group_connected(index_pairs: &[(usize, usize, u32)]) -> Vec<Vec<usize>> {
+    let item_count = index_pairs.iter().map(|&(i, j, _)| i.max(j) + 1).max().unwrap_or(0);
+    let mut components = UnionFind::new(item_count);
+    for &(i, j, _) in index_pairs.iter() {
+        components.union(i, j);
+    }
+    collect_components(&mut components, index_pairs)
+}
+
+/// This function greedily merges groups in ascending order of distance,
+/// but only if every member of the merged group would be within
+/// the threshold of every other member.
+fn group_complete(
+    img_hashes: &[(PathBuf, ImageHash)],
+    index_pairs: &[(usize, usize, u32)],
+    threshold: u32,
+) -> Vec<Vec<usize>> {
+    let item_count = index_pairs.iter().map(|&(i, j, _)| i.max(j) + 1).max().unwrap_or(0);
+    let mut clusters = UnionFind::new(item_count);
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+
+    for &(i, j, _) in index_pairs.iter().sorted_by_key(|&&(i, j, dist)| (dist, i, j)) {
+        let (root_i, root_j) = (clusters.find(i), clusters.find(j));
+        if root_i == root_j {
+            continue;
+        }
+        let members_i = members.remove(&root_i).unwrap_or_else(|| vec![i]);
+        let members_j = members.remove(&root_j).unwrap_or_else(|| vec![j]);
+        let mergeable = members_i
+            .iter()
+            .cartesian_product(members_j.iter())
+            .all(|(&a, &b)| img_hashes[a].1.dist(&img_hashes[b].1) <= threshold);
+        if mergeable {
+            clusters.union(root_i, root_j);
+            let merged = members_i.into_iter().chain(members_j).collect();
+            members.insert(clusters.find(root_i), merged);
+        } else {
+            members.insert(root_i, members_i);
+            members.insert(root_j, members_j);
+        }
+    }
+
+    collect_components(&mut clusters, index_pairs)
+}
+
+/// This function repeatedly picks the ungrouped item with the most
+/// ungrouped neighbours as a center, and groups it with those neighbours.
+///
+/// The candidates are kept in a priority queue, which is updated as their
+/// neighbours get grouped. Outdated entries are skipped when they come up.
+///
+/// Returns each group alongside its center.
+fn group_star(img_hashes: &[(PathBuf, ImageHash)], index_pairs: &[(usize, usize, u32)]) -> Vec<(usize, Vec<usize>)> {
+    let mut neighbours: HashMap<usize, Vec<(usize, u32)>> = HashMap::new();
+    for &(i, j, dist) in index_pairs.iter() {
+        neighbours.entry(i).or_default().push((j, dist));
+        neighbours.entry(j).or_default().push((i, dist));
+    }
+
+    // the number of ungrouped neighbours of each ungrouped item, and the sum of their distances
+    let mut degrees: HashMap<usize, (usize, u64)> = neighbours
+        .iter()
+        .map(|(&i, ns)| (i, (ns.len(), ns.iter().map(|&(_, dist)| u64::from(dist)).sum())))
+        .collect();
+    // ties are broken by the sum of distances to neighbours, then by path
+    let path_ranks: HashMap<usize, usize> = neighbours
+        .keys()
+        .sorted_by_key(|&&i| &img_hashes[i].0)
+        .enumerate()
+        .map(|(rank, &i)| (i, rank))
+        .collect();
+    let entry_of = |i: usize, (degree, sum_dist): (usize, u64)| (degree, Reverse(sum_dist), Reverse(path_ranks[&i]), i);
+    let mut candidates: BinaryHeap<_> = degrees.iter().map(|(&i, &degree)| entry_of(i, degree)).collect();
+
+    let mut groups = vec![];
+    while let Some((degree, Reverse(sum_dist), _, center)) = candidates.pop() {
+        if degree == 0 || degrees.get(&center) != Some(&(degree, sum_dist)) {
+            continue; // grouped or outdated
+        }
+        let members: Vec<_> = std::iter::once(center)
+            .chain(neighbours[&center].iter().map(|&(j, _)| j))
+            .filter(|j| degrees.contains_key(j))
+            .collect();
+        for member in members.iter() {
+            degrees.remove(member);
+        }
+
+        // update the remaining neighbours of the new members
+        let mut updated = HashSet::new();
+        for &member in members.iter() {
+            for &(j, dist) in neighbours[&member].iter() {
+                if let Some((degree, sum_dist)) = degrees.get_mut(&j) {
+                    *degree -= 1;
+                    *sum_dist -= u64::from(dist);
+                    updated.insert(j);
+                }
+            }
+        }
+        candidates.extend(updated.into_iter().map(|j| entry_of(j, degrees[&j])));
+
+        groups.push((center, members));
+    }
+    groups
+}
+
+/// This function collects the items that appear in at least one pair,
+/// grouped by the set they belong to.
+fn collect_components(sets: &mut UnionFind, index_pairs: &[(usize, usize, u32)]) -> Vec<Vec<usize>> {
+    let mut members_by_root: HashMap<usize, Vec<usize>> = HashMap::new();
+    for &(i, j, _) in index_pairs.iter() {
+        for &item in [i, j].iter() {
+            members_by_root.entry(sets.find(item)).or_default().push(item);
+        }
+    }
+    members_by_root
+        .into_values()
+        .map(|mut members| {
+            members.sort_unstable();
+            members.dedup();
+            members
+        })
+        .collect()
+}
+
+/// This function calculates the distance of each member to the representative.
+///
+/// If no representative is specified, the medoid is chosen,
+/// i.e. the member with the smallest maximum distance to all other members.
+fn make_group<'a>(
+    img_hashes: &'a [(PathBuf, ImageHash)],
+    members: &[usize],
+    representative: Option<usize>,
+) -> DupGroup<'a> {
+    let hash_of = |i: usize| &img_hashes[i].1;
+
+    // ties are broken by the sum of distances, then by path
+    let representative = representative.unwrap_or_else(|| {
+        // the maximum and sum of distances of each member to all others, computing each distance once
+        let mut dists = vec![(0, 0u64); members.len()];
+        for (m, n) in (0..members.len()).tuple_combinations() {
+            let dist = hash_of(members[m]).dist(hash_of(members[n]));
+            for k in [m, n] {
+                dists[k] = (dists[k].0.max(dist), dists[k].1 + u64::from(dist));
+            }
+        }
+        members
+            .iter()
+            .zip(dists)
+            .min_by_key(|&(&i, (max_dist, sum_dist))| (max_dist, sum_dist, &img_hashes[i].0))
+            .map(|(&i, _)| i)
+            .expect("A group cannot be empty")
+    });
+
+    let mut group_members: Vec<_> = members
+        .iter()
+        .map(|&i| (img_hashes[i].0.as_path(), hash_of(representative).dist(hash_of(i)), i))
+        .collect();
+    // the representative always goes first, even if others are at distance 0
+    group_members.sort_by_key(|&(path, dist, i)| (i != representative, dist, path));
+
+    DupGroup {
+        members: group_members.iter().map(|&(path, dist, _)| (path, dist)).collect(),
+        hashes: group_members.iter().map(|&(_, _, i)| hash_of(i)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Images `p0` to `p6` on a line, where the distance between `pi` and `pj` is `|i - j|`.
+    fn line_of_images() -> Vec<(PathBuf, ImageHash)> {
+        (0..7)
+            .map(|n| {
+                let hash = ImageHash::from_bytes(&[(1u16 << n) as u8 - 1, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+                (PathBuf::from(format!("p{}", n)), hash)
+            })
+            .collect()
+    }
+
+    /// Group the images, with all pairs within the threshold as similar pairs.
+    fn groups_of(img_hashes: &[(PathBuf, ImageHash)], linkage: Linkage, threshold: u32) -> Vec<(String, Vec<String>)> {
+        let similar_pairs: Vec<_> = img_hashes
+            .iter()
+            .tuple_combinations()
+            .map(|((p0, h0), (p1, h1))| (p0.as_path(), p1.as_path(), h0.dist(h1)))
+            .filter(|&(_, _, dist)| dist <= threshold)
+            .collect();
+        calc_groups(img_hashes, &similar_pairs, linkage, threshold)
+            .iter()
+            .map(|group| {
+                let name = |path: &Path| path.display().to_string();
+                let members = group.members.iter().map(|&(path, _)| name(path)).sorted().collect();
+                (name(group.representative()), members)
+            })
+            .collect()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn line_distances() {
+        let img_hashes = line_of_images();
+        assert_eq!(img_hashes[1].1.dist(&img_hashes[4].1), 3);
+        assert_eq!(img_hashes[0].1.dist(&img_hashes[6].1), 6);
+    }
+
+    #[test]
+    fn single_linkage() {
+        // chains link the whole line into one group, around its middle
+        let groups = groups_of(&line_of_images(), Linkage::Single, 1);
+        assert_eq!(
+            groups,
+            vec![("p3".into(), names(&["p0", "p1", "p2", "p3", "p4", "p5", "p6"]))]
+        );
+
+        // images without similar pairs are not grouped
+        let mut img_hashes = line_of_images();
+        img_hashes.push((
+            "far".into(),
+            ImageHash::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0xff]).unwrap(),
+        ));
+        let groups = groups_of(&img_hashes, Linkage::Single, 1);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups_of(&img_hashes, Linkage::Single, 0), vec![]);
+    }
+
+    #[test]
+    fn complete_linkage() {
+        let groups = groups_of(&line_of_images(), Linkage::Complete, 2);
+        assert_eq!(
+            groups,
+            vec![
+                ("p1".into(), names(&["p0", "p1", "p2"])),
+                ("p4".into(), names(&["p3", "p4", "p5"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn star_linkage() {
+        let groups = groups_of(&line_of_images(), Linkage::Star, 1);
+        assert_eq!(
+            groups,
+            vec![
+                ("p1".into(), names(&["p0", "p1", "p2"])),
+                ("p4".into(), names(&["p3", "p4", "p5"])),
+            ]
+        );
+
+        let groups = groups_of(&line_of_images(), Linkage::Star, 2);
+        assert_eq!(
+            groups,
+            vec![
+                ("p2".into(), names(&["p0", "p1", "p2", "p3", "p4"])),
+                ("p5".into(), names(&["p5", "p6"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn group_members_and_diameter() {
+        let img_hashes = line_of_images();
+        let group = make_group(&img_hashes, &[2, 3, 4, 5], None);
+        // the medoid is p3 (or p4), with ties broken by path
+        assert_eq!(group.representative(), Path::new("p3"));
+        let dists: Vec<_> = group.members.iter().map(|&(_, dist)| dist).collect();
+        assert_eq!(dists, vec![0, 1, 1, 2]);
+        assert_eq!(group.diameter(), 3);
+
+        let group = make_group(&img_hashes, &[2, 3, 4, 5], Some(5));
+        assert_eq!(group.representative(), Path::new("p5"));
+        assert_eq!(group.dist(0, 3), 3);
+    }
+}
@@ -7,20 +7,65 @@ use std::{
 };
 
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use image::DynamicImage;
+use image::GenericImageView;
 use img_hash::{HashAlg, HasherConfig, ImageHash};
-use itertools::Itertools;
+
+use crate::{
+    banding::BandIndex,
+    bk_tree::BkTree,
+    io::LoadedImage,
+    preview::{encode_jpeg, make_thumbnail},
+};
+
+/// The strategy used to search for similar pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchMode {
+    /// Exact radius search using a [`BkTree`].
+    Exact,
+    /// Approximate candidate generation using a [`BandIndex`]
+    /// with the specified number of bands, followed by exact verification.
+    Banded(usize),
+}
+impl SearchMode {
+    /// A banded search needs more bands than the threshold not to miss any pair,
+    /// but a hash cannot be split into more bands than it has bits.
+    /// If the threshold is not below the number of bits in the hashes,
+    /// fall back to an exact search.
+    pub fn fit_to_hashes(self, img_hashes: &[(PathBuf, ImageHash)], threshold: u32) -> Self {
+        let bit_count = img_hashes
+            .first()
+            .map(|(_, hash)| hash.as_bytes().len() * 8)
+            .unwrap_or(0);
+        match self {
+            SearchMode::Banded(_) if threshold as usize >= bit_count => SearchMode::Exact,
+            mode => mode,
+        }
+    }
+}
+
+/// The path of an image, and its thumbnail encoded by [`encode_jpeg`].
+pub type EncodedThumbnail = (PathBuf, Vec<u8>);
+
+/// A thumbnail encoded by [`encode_jpeg`], and the original dimensions of its image.
+pub type SizedThumbnail = (Vec<u8>, (u32, u32));
 
 /// This function receives a list of parsed images via a channel,
 /// computes their perceptual hash using the specified settings,
 /// and sends the result via another channel.
 ///
+/// Images whose hashes were retrieved from the cache are passed through as-is.
+///
+/// If a thumbnail channel and size are provided, a thumbnail of each decoded image
+/// is also sent via that channel, encoded as JPEG to keep it compact,
+/// to be previewed without decoding the image again.
+///
 /// This operation will always spawn the number of threads
 /// as specified by its argument, even in cases where it's overkill,
 /// because we don't know how many (or rather, few) files we have to process.
 pub fn calc_hashes(
-    imgs_rx: Receiver<(PathBuf, DynamicImage)>,
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
     hashes_tx: Sender<(PathBuf, ImageHash)>,
+    thumbnails: Option<(Sender<EncodedThumbnail>, u32)>,
     thread_count: usize,
     algorithm: HashAlg,
     hash_size: (u32, u32),
@@ -29,14 +74,29 @@ pub fn calc_hashes(
         .map(|_| {
             let imgs_rx_local = imgs_rx.clone();
             let hashes_tx_local = hashes_tx.clone();
+            let thumbnails_local = thumbnails.clone();
             thread::spawn(move || {
                 let hasher = HasherConfig::new()
                     .hash_alg(algorithm)
                     .hash_size(hash_size.0, hash_size.1)
                     .to_hasher();
                 // compute hash and send until empty and disconnected
-                imgs_rx_local.iter().for_each(|(path, img)| {
-                    let path_hash_pair = (path, hasher.hash_image(&img));
+                imgs_rx_local.iter().for_each(|(path, loaded)| {
+                    let hash = match loaded {
+                        LoadedImage::Decoded(img) => {
+                            if let Some((thumbs_tx, size)) = &thumbnails_local {
+                                // an image whose thumbnail cannot be encoded is decoded again if previewed
+                                if let Ok(jpeg) = encode_jpeg(&make_thumbnail(&img, *size)) {
+                                    thumbs_tx
+                                        .send((path.clone(), jpeg))
+                                        .expect("Thumbnail receiver hung up unexpectedly");
+                                }
+                            }
+                            hasher.hash_image(&img)
+                        }
+                        LoadedImage::Cached(hash) => hash,
+                    };
+                    let path_hash_pair = (path, hash);
                     hashes_tx_local
                         .send(path_hash_pair)
                         .expect("Hash receiver hung up unexpectedly");
@@ -48,6 +108,7 @@ pub fn calc_hashes(
     // manually drop the implicitly held sender and receiver as per best practice
     drop(imgs_rx);
     drop(hashes_tx);
+    drop(thumbnails);
 
     // wait for all workers to finish
     join_handles.into_iter().for_each(|h| {
@@ -56,64 +117,331 @@ pub fn calc_hashes(
 }
 
 /// This function takes a list of image paths and their hashes,
-/// turns them into n * (n - 1) pairs via combination,
-/// calculates the perceptual hamming distance between the two,
+/// indexes them in a [`BkTree`], finds all pairs whose
+/// perceptual hamming distance is within the threshold (inclusive),
 /// and collects them into a Vec.
 ///
+/// Unlike comparing every possible pair, this only visits the
+/// parts of the tree that can possibly contain similar hashes.
+/// Also returns the number of hamming distances that were computed.
+///
+/// Pairs are sorted as per [`sort_pairs`].
+///
 /// This operation will always spawn the number of threads
 /// as specified by its argument, even in cases where it's overkill,
-/// because we don't know how many (or rather, few) pairs we have to process.
-pub fn calc_pair_dist(img_hashes: &[(PathBuf, ImageHash)], thread_count: usize) -> Vec<(&Path, &Path, u32)> {
+/// because we don't know how many (or rather, few) hashes we have to process.
+pub fn calc_similar_pairs(
+    img_hashes: &[(PathBuf, ImageHash)],
+    threshold: u32,
+    thread_count: usize,
+) -> (Vec<(&Path, &Path, u32)>, usize) {
     use crossbeam::thread;
 
-    // create pairs with Itertools
-    let pairs: Vec<_> = img_hashes.iter().tuple_combinations::<(_, _)>().collect();
+    // build index
+    let tree = BkTree::new(img_hashes);
 
     // create channels
-    let (pairs_tx, pairs_rx): (Sender<(&(PathBuf, ImageHash), &(PathBuf, ImageHash))>, _) = unbounded();
-    let (dists_tx, dists_rx) = unbounded();
+    let (items_tx, items_rx) = unbounded();
+    let (pairs_tx, pairs_rx) = unbounded();
+    let (counts_tx, counts_rx) = unbounded();
 
     // using scoped thread guarantees workers terminate before caller thread,
     // ... thereby satisfying lifetime constraints
-    thread::scope(move |s| {
+    thread::scope(|s| {
         let join_handles: Vec<_> = (0..thread_count)
             .map(|_| {
-                let pairs_rx_local = pairs_rx.clone();
-                let dists_tx_local = dists_tx.clone();
+                let items_rx_local: Receiver<usize> = items_rx.clone();
+                let pairs_tx_local = pairs_tx.clone();
+                let counts_tx_local = counts_tx.clone();
+                let tree = &tree;
                 s.spawn(move |_| {
-                    // compute distance and send until empty and disconnected
-                    pairs_rx_local.iter().for_each(|((p0, h0), (p1, h1))| {
-                        let dist = h0.dist(h1);
-                        dists_tx_local
-                            .send((p0, p1, dist))
-                            .expect("Hash receiver hung up unexpectedly");
+                    // query neighbours and send until empty and disconnected
+                    let mut dist_count = 0;
+                    items_rx_local.iter().for_each(|i| {
+                        let (found, query_dist_count) = tree.find_within(&img_hashes[i].1, threshold);
+                        dist_count += query_dist_count;
+                        found
+                            .into_iter()
+                            .filter(|&(j, _)| i < j) // report each pair only once
+                            .for_each(|(j, dist)| {
+                                pairs_tx_local
+                                    .send((i, j, dist))
+                                    .expect("Pair receiver hung up unexpectedly");
+                            });
                     });
+                    counts_tx_local
+                        .send(dist_count)
+                        .expect("Count receiver hung up unexpectedly");
                 })
             })
             .collect();
 
         // manually drop the implicitly held sender and receiver as per best practice
-        drop(pairs_rx);
-        drop(dists_tx);
-
-        // send hash-pairs to workers
-        pairs.into_iter().for_each(|pair| {
-            pairs_tx
-                .send(pair)
-                .expect("All hash-pair receivers hung up unexpectedly");
+        drop(items_rx);
+        drop(pairs_tx);
+        drop(counts_tx);
+
+        // send items to workers
+        (0..img_hashes.len()).for_each(|i| {
+            items_tx.send(i).expect("All item receivers hung up unexpectedly");
+        });
+        // close items producer
+        drop(items_tx);
+
+        // wait for all workers to finish
+        join_handles.into_iter().for_each(|h| {
+            h.join().expect("A search worker thread panicked unexpectedly");
         });
-        // close pairs producer
+    })
+    .unwrap(); // cannot be Err; panicked worker threads already caught by manual join
+
+    let pairs = pairs_rx
+        .into_iter()
+        .map(|(i, j, d)| (img_hashes[i].0.as_path(), img_hashes[j].0.as_path(), d))
+        .collect();
+    (sort_pairs(pairs), counts_rx.into_iter().sum())
+}
+
+/// This function takes a list of image paths and their hashes,
+/// splits them into bands using a [`BandIndex`], verifies the hamming distance
+/// of all pairs that share at least one band, and collects the pairs within
+/// the threshold (inclusive) into a Vec.
+///
+/// Also returns the number of candidate pairs that were examined,
+/// and the number of bands actually used.
+///
+/// Pairs are sorted as per [`sort_pairs`].
+///
+/// This operation will always spawn the number of threads
+/// as specified by its argument, even in cases where it's overkill,
+/// because we don't know how many (or rather, few) buckets we have to process.
+pub fn calc_similar_pairs_banded(
+    img_hashes: &[(PathBuf, ImageHash)],
+    threshold: u32,
+    band_count: usize,
+    thread_count: usize,
+) -> (Vec<(&Path, &Path, u32)>, usize, usize) {
+    use crossbeam::thread;
+
+    // build index
+    let index = BandIndex::new(img_hashes, band_count);
+
+    // create channels
+    let (buckets_tx, buckets_rx) = unbounded();
+    let (pairs_tx, pairs_rx) = unbounded();
+    let (counts_tx, counts_rx) = unbounded();
+
+    // using scoped thread guarantees workers terminate before caller thread,
+    // ... thereby satisfying lifetime constraints
+    thread::scope(|s| {
+        let join_handles: Vec<_> = (0..thread_count)
+            .map(|_| {
+                let buckets_rx_local: Receiver<(usize, &[usize])> = buckets_rx.clone();
+                let pairs_tx_local = pairs_tx.clone();
+                let counts_tx_local = counts_tx.clone();
+                let index = &index;
+                s.spawn(move |_| {
+                    // verify candidates and send until empty and disconnected
+                    let mut candidate_count = 0;
+                    buckets_rx_local.iter().for_each(|(band, items)| {
+                        for (n, &i) in items.iter().enumerate() {
+                            for &j in items[n + 1..].iter() {
+                                if !index.is_first_shared_band(i, j, band) {
+                                    continue;
+                                }
+                                candidate_count += 1;
+                                let dist = img_hashes[i].1.dist(&img_hashes[j].1);
+                                if dist <= threshold {
+                                    pairs_tx_local
+                                        .send((i, j, dist))
+                                        .expect("Pair receiver hung up unexpectedly");
+                                }
+                            }
+                        }
+                    });
+                    counts_tx_local
+                        .send(candidate_count)
+                        .expect("Count receiver hung up unexpectedly");
+                })
+            })
+            .collect();
+
+        // manually drop the implicitly held sender and receiver as per best practice
+        drop(buckets_rx);
         drop(pairs_tx);
+        drop(counts_tx);
+
+        // send buckets to workers
+        index.buckets().for_each(|bucket| {
+            buckets_tx
+                .send(bucket)
+                .expect("All bucket receivers hung up unexpectedly");
+        });
+        // close buckets producer
+        drop(buckets_tx);
 
         // wait for all workers to finish
         join_handles.into_iter().for_each(|h| {
-            h.join().expect("A distance worker thread panicked unexpectedly");
+            h.join().expect("A verification worker thread panicked unexpectedly");
         });
     })
     .unwrap(); // cannot be Err; panicked worker threads already caught by manual join
 
-    dists_rx
+    let pairs = pairs_rx
+        .into_iter()
+        .map(|(i, j, d)| (img_hashes[i].0.as_path(), img_hashes[j].0.as_path(), d))
+        .collect();
+    (sort_pairs(pairs), counts_rx.into_iter().sum(), index.band_count())
+}
+
+/// This function puts similar pairs in a deterministic order, regardless of
+/// the order in which images were loaded and pairs were found by worker threads.
+///
+/// The paths within each pair are sorted, and pairs are sorted
+/// by distance in ascending order, then by their paths.
+fn sort_pairs<'a>(pairs: Vec<(&'a Path, &'a Path, u32)>) -> Vec<(&'a Path, &'a Path, u32)> {
+    let mut pairs: Vec<_> = pairs
         .into_iter()
-        .map(|(p0, p1, d)| (p0.as_path(), p1.as_path(), d))
-        .collect()
+        .map(|(p0, p1, dist)| (p0.min(p1), p0.max(p1), dist))
+        .collect();
+    pairs.sort_unstable_by_key(|&(p0, p1, dist)| (dist, p0, p1));
+    pairs
+}
+
+/// This function decodes a list of images, and shrinks each of them
+/// to a thumbnail fitting within `size`×`size` pixels, encoded by [`encode_jpeg`].
+///
+/// Returns the encoded thumbnail and the original dimensions of each image,
+/// in the same order as the paths, or `None` for images that cannot be decoded.
+///
+/// This operation will always spawn the number of threads
+/// as specified by its argument, even in cases where it's overkill.
+pub fn calc_thumbnails(paths: &[&Path], size: u32, thread_count: usize) -> Vec<Option<SizedThumbnail>> {
+    use crossbeam::thread;
+
+    // create channels
+    let (items_tx, items_rx) = unbounded();
+    let (thumbs_tx, thumbs_rx) = unbounded();
+
+    // using scoped thread guarantees workers terminate before caller thread,
+    // ... thereby satisfying lifetime constraints
+    thread::scope(|s| {
+        let join_handles: Vec<_> = (0..thread_count)
+            .map(|_| {
+                let items_rx_local: Receiver<usize> = items_rx.clone();
+                let thumbs_tx_local = thumbs_tx.clone();
+                s.spawn(move |_| {
+                    // decode and send until empty and disconnected
+                    items_rx_local.iter().for_each(|i| {
+                        // encoded right away, so that only compact thumbnails are collected
+                        let thumb = image::open(paths[i]).ok().and_then(|img| {
+                            let jpeg = encode_jpeg(&make_thumbnail(&img, size)).ok()?;
+                            Some((jpeg, img.dimensions()))
+                        });
+                        thumbs_tx_local
+                            .send((i, thumb))
+                            .expect("Thumbnail receiver hung up unexpectedly");
+                    });
+                })
+            })
+            .collect();
+
+        // manually drop the implicitly held sender and receiver as per best practice
+        drop(items_rx);
+        drop(thumbs_tx);
+
+        // send items to workers
+        (0..paths.len()).for_each(|i| {
+            items_tx.send(i).expect("All item receivers hung up unexpectedly");
+        });
+        // close items producer
+        drop(items_tx);
+
+        // wait for all workers to finish
+        join_handles.into_iter().for_each(|h| {
+            h.join().expect("A thumbnail worker thread panicked unexpectedly");
+        });
+    })
+    .unwrap(); // cannot be Err; panicked worker threads already caught by manual join
+
+    let mut thumbs = vec![None; paths.len()];
+    thumbs_rx.into_iter().for_each(|(i, thumb)| thumbs[i] = thumb);
+    thumbs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+
+    /// Images whose hashes have the lowest `n` bits set, loaded in reverse order,
+    /// so that the distance between two of them is the difference of their `n`s.
+    fn stacked_images() -> Vec<(PathBuf, ImageHash)> {
+        (0..12)
+            .rev()
+            .map(|n| {
+                let bits = (1u64 << n) - 1;
+                (
+                    PathBuf::from(format!("{:02}.jpg", n)),
+                    ImageHash::from_bytes(&bits.to_le_bytes()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn similar_pairs_match_brute_force() {
+        let img_hashes = stacked_images();
+        for threshold in 0..4 {
+            let (pairs, _) = calc_similar_pairs(&img_hashes, threshold, 4);
+            let expected: Vec<_> = img_hashes
+                .iter()
+                .tuple_combinations()
+                .map(|((p0, h0), (p1, h1))| (p0.as_path().min(p1), p0.as_path().max(p1), h0.dist(h1)))
+                .filter(|&(_, _, dist)| dist <= threshold)
+                .sorted_by_key(|&(p0, p1, dist)| (dist, p0, p1))
+                .collect();
+            assert_eq!(pairs, expected, "threshold {}", threshold);
+        }
+    }
+
+    #[test]
+    fn banded_falls_back_to_exact_if_threshold_exceeds_bits() {
+        let img_hashes = vec![
+            (PathBuf::from("0.jpg"), ImageHash::from_bytes(&[0x00; 8]).unwrap()),
+            (PathBuf::from("1.jpg"), ImageHash::from_bytes(&[0xff; 8]).unwrap()),
+        ];
+        assert_eq!(
+            SearchMode::Banded(64).fit_to_hashes(&img_hashes, 63),
+            SearchMode::Banded(64)
+        );
+        assert_eq!(SearchMode::Banded(65).fit_to_hashes(&img_hashes, 64), SearchMode::Exact);
+
+        // even one band per bit would miss the pair
+        let (pairs, _, band_count) = calc_similar_pairs_banded(&img_hashes, 64, 65, 2);
+        assert_eq!(band_count, 64);
+        assert!(pairs.is_empty());
+        let (pairs, _) = calc_similar_pairs(&img_hashes, 64, 2);
+        assert_eq!(pairs, vec![(Path::new("0.jpg"), Path::new("1.jpg"), 64)]);
+    }
+
+    #[test]
+    fn similar_pairs_are_sorted() {
+        let img_hashes = stacked_images();
+        let (pairs, _) = calc_similar_pairs(&img_hashes, 1, 4);
+        let names: Vec<_> = pairs
+            .iter()
+            .take(3)
+            .map(|(p0, p1, dist)| (p0.to_str().unwrap(), p1.to_str().unwrap(), *dist))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("00.jpg", "01.jpg", 1),
+                ("01.jpg", "02.jpg", 1),
+                ("02.jpg", "03.jpg", 1)
+            ]
+        );
+        assert_eq!(pairs.len(), 11);
+    }
 }
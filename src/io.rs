@@ -6,61 +6,179 @@
 
 use crossbeam_channel::Sender;
 use image::DynamicImage;
+use img_hash::ImageHash;
 use regex::Regex;
 use std::{
-    fs::ReadDir,
+    collections::HashSet,
     path::{Path, PathBuf},
+    sync::Mutex,
 };
+use walkdir::WalkDir;
 
-/// This function tries to load all files in the opened directory
-/// that matches the filter, tries to parse them into images,
-/// and then send them via a channel.
+use crate::cache::HashCache;
+
+/// A loaded input file, ready to be hashed.
+pub enum LoadedImage {
+    /// The file was decoded, and its hash needs to be computed.
+    Decoded(DynamicImage),
+    /// The file has a valid entry in the hash cache, and was not decoded.
+    Cached(ImageHash),
+}
+
+/// Options that control how input directories are traversed.
+#[derive(Debug, Clone, Copy)]
+pub struct WalkOptions {
+    /// The maximum depth to descend to, where files directly inside
+    /// an input directory are at depth 1. `None` means unlimited.
+    pub max_depth: Option<usize>,
+    /// Whether symbolic links are followed. If not, they are skipped.
+    pub follow_symlinks: bool,
+}
+
+/// This function recursively walks each of the input roots,
+/// tries to load all files that match the filter,
+/// tries to parse them into images, and then send them via a channel.
 ///
 /// This is a single-threaded operation.
 ///
+/// A root can be either a directory or a single file. Files specified
+/// directly as roots are always accepted, regardless of the filter.
+/// A file that is reachable from multiple roots (or via multiple symlinks)
+/// is only loaded once.
+///
+/// If a hash cache is provided, files with a valid cache entry are not decoded;
+/// their cached hashes are sent instead.
+///
 /// If an error is encountered while loading or parsing an individual file,
 /// it will be logged to console and skipped.
-pub fn load_in(imgs_tx: Sender<(PathBuf, DynamicImage)>, opened_in_dir: ReadDir, in_filter: &Regex) {
-    let selected_files: Vec<_> = opened_in_dir // short-circuit if error while opening directory
-        // iter over io::Result<DirEntry>
-        .map(|de_res| de_res.map(|de| de.path()))
-        // iter over io::Result<PathBuf>
-        .filter_map(|res| match res {
-            Ok(path) => Some(path),
-            Err(e) => {
-                println!("Failed to open a file: {:?}", e);
-                None
-            }
+pub fn load_in(
+    imgs_tx: Sender<(PathBuf, LoadedImage)>,
+    roots: &[PathBuf],
+    walk_opts: WalkOptions,
+    in_filter: &Regex,
+    cache: Option<&Mutex<HashCache>>,
+) {
+    let mut seen_files = HashSet::new();
+    let selected_files: Vec<_> = roots
+        .iter()
+        .flat_map(|root| match root.is_dir() {
+            true => walk_dir(root, walk_opts, in_filter),
+            false => match root.to_str() {
+                Some(_) => vec![root.clone()],
+                None => {
+                    eprintln!("File path is not a valid utf-8 string: {:?}", root);
+                    vec![]
+                }
+            },
         })
-        // iter over PathBuf
-        .filter(|path| match path.to_str() {
-            Some(path_str) => in_filter.is_match(path_str),
-            None => {
-                println!("File path is not a valid utf-8 string: {:?}", path);
+        // iter over PathBuf (from all roots)
+        .filter(|path| match path.canonicalize() {
+            Ok(canonical_path) => seen_files.insert(canonical_path),
+            Err(e) => {
+                eprintln!("Failed to resolve {:?}: {:?}", path, e);
                 false
             }
         })
-        // iter over PathBuf (filtered)
+        // iter over PathBuf (deduplicated)
         .collect();
 
     for path in selected_files.into_iter() {
-        // read file and send to buffer
-        match image::open(&path) {
-            Ok(img) => {
-                let send_res = imgs_tx.send((path.clone(), img)); // blocks if channel is full
+        // skip decoding if hash is cached
+        let cached_hash = cache.and_then(|c| c.lock().unwrap().lookup(&path));
+        let load_res = match cached_hash {
+            Some(hash) => Ok(LoadedImage::Cached(hash)),
+            None => image::open(&path).map(LoadedImage::Decoded),
+        };
+
+        // send to buffer
+        match load_res {
+            Ok(loaded) => {
+                let send_res = imgs_tx.send((path.clone(), loaded)); // blocks if channel is full
                 if let Err(e) = send_res {
-                    println!("All image receivers hang up unexpectedly: {:?}", e);
-                    println!("Image loading will stop now");
+                    eprintln!("All image receivers hang up unexpectedly: {:?}", e);
+                    eprintln!("Image loading will stop now");
                     break;
                 }
             }
             Err(e) => {
-                println!("Failed to load {:?} as image: {:?}", &path, e);
+                eprintln!("Failed to load {:?} as image: {:?}", &path, e);
             }
         };
     }
 }
 
+/// This function recursively walks a single input directory,
+/// and collects the paths of all regular files that match the filter.
+///
+/// Directories are descended into (up to the maximum depth), but are never
+/// considered for loading themselves, even if they match the filter.
+/// If symbolic links are followed, loops are detected and skipped.
+fn walk_dir(in_dir: &Path, walk_opts: WalkOptions, in_filter: &Regex) -> Vec<PathBuf> {
+    let mut walker = WalkDir::new(in_dir)
+        .min_depth(1) // skip the input directory itself
+        .follow_links(walk_opts.follow_symlinks);
+    if let Some(depth) = walk_opts.max_depth {
+        walker = walker.max_depth(depth);
+    }
+
+    walker
+        .into_iter()
+        // iter over walkdir::Result<DirEntry>
+        .filter_map(|res| match res {
+            Ok(de) => Some(de),
+            Err(e) => {
+                match (e.path(), e.loop_ancestor()) {
+                    (Some(path), Some(ancestor)) => {
+                        eprintln!("Symlink loop detected: {:?} points to {:?}; skipping", path, ancestor)
+                    }
+                    _ => eprintln!("Failed to open a file: {:?}", e),
+                }
+                None
+            }
+        })
+        // iter over DirEntry
+        .filter(|de| {
+            // if symlinks are followed, this is the file type of the link target
+            let file_type = de.file_type();
+            if file_type.is_dir() {
+                // already walked into by the iterator, nothing to load
+                false
+            } else if file_type.is_symlink() {
+                eprintln!("Skipping symlink: {:?}", de.path());
+                false
+            } else if !file_type.is_file() {
+                eprintln!("Skipping special file: {:?}", de.path());
+                false
+            } else {
+                true
+            }
+        })
+        // iter over DirEntry (regular files only)
+        .map(|de| de.into_path())
+        // iter over PathBuf
+        .filter(|path| match path.to_str() {
+            Some(path_str) => in_filter.is_match(path_str),
+            None => {
+                eprintln!("File path is not a valid utf-8 string: {:?}", path);
+                false
+            }
+        })
+        // iter over PathBuf (filtered)
+        .collect()
+}
+
+/// This function finds the input root that a path was loaded from.
+///
+/// If the path is reachable from multiple (nested) roots,
+/// the innermost one is returned.
+pub fn find_root<'a>(path: &Path, roots: &'a [PathBuf]) -> Option<&'a Path> {
+    roots
+        .iter()
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.components().count())
+        .map(|root| root.as_path())
+}
+
 /// This function takes the filename from a path,
 /// and tries to parse it into a UTF-8 string,
 /// panicking if it's unable to do so.
@@ -99,7 +217,7 @@ pub fn test_write_to_dir(dir: &Path) -> std::io::Result<()> {
             let mut test_path = dir.to_path_buf();
             test_path.push(format!("img-dedup-write-test-{}.tmp", n));
 
-            (!test_path.exists()).then(|| test_path)
+            (!test_path.exists()).then_some(test_path)
         })
         .unwrap(); // will find one eventually
 
@@ -111,3 +229,585 @@ pub fn test_write_to_dir(dir: &Path) -> std::io::Result<()> {
 
     Ok(())
 }
+
+/// What to do when a file with the same name already exists at the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionStrategy {
+    /// Append the smallest unused numeric suffix (e.g. `IMG_0001-1.jpg`).
+    Suffix,
+    /// Append a short checksum of the file's content (e.g. `IMG_0001-3fa2b81c.jpg`),
+    /// falling back to a numeric suffix if that is also taken.
+    Hash,
+    /// Leave the file where it is.
+    Skip,
+    /// Stop processing any further files.
+    Abort,
+    /// Replace the existing file, unless it was itself moved there in the same batch,
+    /// in which case a numeric suffix is appended instead.
+    Overwrite,
+}
+
+/// This function decides the destination path of a file being moved
+/// (or copied) into a directory, retaining its original file name if possible.
+///
+/// `claimed` contains the destination paths already assigned to other files
+/// in the same batch, which are treated as taken even if they do not exist yet.
+/// The returned path is added to it.
+///
+/// Returns `Ok(None)` if the file should be skipped,
+/// or Err if the batch should be aborted.
+pub fn resolve_dest_path(
+    from_path: &Path,
+    dest_dir: &Path,
+    strategy: CollisionStrategy,
+    claimed: &mut HashSet<PathBuf>,
+) -> Result<Option<PathBuf>, String> {
+    let is_taken = |path: &Path, claimed: &HashSet<PathBuf>| claimed.contains(path) || path.exists();
+    let with_suffix = |suffix: &str| {
+        let stem = from_path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match from_path.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, suffix, ext.to_string_lossy()),
+            None => format!("{}-{}", stem, suffix),
+        };
+        dest_dir.join(name)
+    };
+    let with_num_suffix = |base: &str, claimed: &HashSet<PathBuf>| {
+        (1..)
+            .map(|n| match base {
+                "" => with_suffix(&n.to_string()),
+                base => with_suffix(&format!("{}-{}", base, n)),
+            })
+            .find(|path| !is_taken(path, claimed))
+            .unwrap() // will find one eventually
+    };
+
+    let dest_path = dest_dir.join(get_filename_unchecked(from_path));
+    let resolved = if !is_taken(&dest_path, claimed) {
+        Some(dest_path)
+    } else {
+        match strategy {
+            CollisionStrategy::Suffix => Some(with_num_suffix("", claimed)),
+            CollisionStrategy::Hash => {
+                let checksum = file_checksum(from_path).map_err(|e| e.to_string())?;
+                let hashed_path = with_suffix(&checksum[..8]);
+                match is_taken(&hashed_path, claimed) {
+                    false => Some(hashed_path),
+                    true => Some(with_num_suffix(&checksum[..8], claimed)),
+                }
+            }
+            CollisionStrategy::Skip => None,
+            CollisionStrategy::Abort => return Err(format!("{:?} already exists; aborting as requested", dest_path)),
+            // never replace a file moved earlier in the same batch
+            CollisionStrategy::Overwrite => match claimed.contains(&dest_path) {
+                true => Some(with_num_suffix("", claimed)),
+                false => Some(dest_path),
+            },
+        }
+    };
+
+    if let Some(path) = &resolved {
+        claimed.insert(path.clone());
+    }
+    Ok(resolved)
+}
+
+/// This function computes the SHA-256 checksum of a file's content,
+/// formatted as a lowercase hex string.
+pub fn file_checksum(path: &Path) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+    use std::{fs::File, io::copy};
+
+    let mut hasher = Sha256::new();
+    copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// This function checks whether two files have identical content,
+/// by comparing their sizes, and then their checksums.
+pub fn has_same_content(a: &Path, b: &Path) -> std::io::Result<bool> {
+    use std::fs::metadata;
+
+    if metadata(a)?.len() != metadata(b)?.len() {
+        return Ok(false);
+    }
+    Ok(file_checksum(a)? == file_checksum(b)?)
+}
+
+/// How a file was moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveMethod {
+    /// The file was renamed (or hard-linked) within the same filesystem.
+    Renamed,
+    /// The file was on another filesystem, so it was copied,
+    /// verified, and then deleted from its original location.
+    CopiedAcrossFilesystems,
+}
+
+/// This function moves a file, falling back to [`copy_verify_delete`]
+/// if the destination is on another filesystem.
+///
+/// Unless `overwrite` is set, an existing file at the destination
+/// is never replaced; this fails with `AlreadyExists` instead.
+/// Where supported, this is done atomically by creating a hard link
+/// and then removing the original. Otherwise, it falls back to
+/// checking for existence before renaming.
+pub fn move_file(from_path: &Path, to_path: &Path, overwrite: bool) -> std::io::Result<MoveMethod> {
+    use std::{
+        fs::{hard_link, remove_file, rename},
+        io::{Error, ErrorKind},
+    };
+
+    let move_res = match overwrite {
+        true => rename(from_path, to_path),
+        false => match hard_link(from_path, to_path) {
+            Ok(_) => remove_file(from_path),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(e),
+            Err(_) if to_path.exists() => Err(Error::new(ErrorKind::AlreadyExists, "destination already exists")),
+            Err(_) => rename(from_path, to_path),
+        },
+    };
+    match move_res {
+        Ok(_) => Ok(MoveMethod::Renamed),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            copy_verify_delete(from_path, to_path, overwrite).map(|_| MoveMethod::CopiedAcrossFilesystems)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// This function moves a file to another filesystem by:
+/// 1. copying it to a temporary file next to the destination,
+/// 2. flushing the copy to disk,
+/// 3. verifying that its size and checksum match the original,
+/// 4. preserving the original's permissions and timestamps,
+/// 5. renaming the copy into place (respecting `overwrite`),
+/// 6. and only then deleting the original.
+///
+/// If any step fails, the original is left untouched and the copy is removed.
+fn copy_verify_delete(from_path: &Path, to_path: &Path, overwrite: bool) -> std::io::Result<()> {
+    use std::{
+        fs::{self, File, FileTimes, OpenOptions},
+        io::{copy, Error, ErrorKind},
+    };
+
+    let dest_dir = to_path.parent().unwrap_or_else(|| Path::new("."));
+    let tmp_path = (0..)
+        .map(|n| dest_dir.join(format!(".img-dedup-copy-{}.tmp", n)))
+        .find(|path| !path.exists())
+        .unwrap(); // will find one eventually
+
+    let copy_res = (|| {
+        let src_meta = fs::metadata(from_path)?;
+
+        // copy and flush to disk
+        let mut src = File::open(from_path)?;
+        let mut tmp = OpenOptions::new().write(true).create_new(true).open(&tmp_path)?;
+        copy(&mut src, &mut tmp)?;
+        tmp.sync_all()?;
+
+        // verify
+        let tmp_meta = tmp.metadata()?;
+        if tmp_meta.len() != src_meta.len() {
+            return Err(Error::other(format!(
+                "size mismatch after copy ({} vs {} bytes)",
+                tmp_meta.len(),
+                src_meta.len()
+            )));
+        }
+        if file_checksum(&tmp_path)? != file_checksum(from_path)? {
+            return Err(Error::other("checksum mismatch after copy"));
+        }
+
+        // preserve metadata
+        tmp.set_permissions(src_meta.permissions())?;
+        let mut times = FileTimes::new().set_modified(src_meta.modified()?);
+        if let Ok(atime) = src_meta.accessed() {
+            times = times.set_accessed(atime);
+        }
+        tmp.set_times(times)?;
+        drop(tmp);
+
+        // move into place
+        match overwrite {
+            true => fs::rename(&tmp_path, to_path),
+            false => match fs::hard_link(&tmp_path, to_path) {
+                Ok(_) => fs::remove_file(&tmp_path),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(e),
+                Err(_) if to_path.exists() => Err(Error::new(ErrorKind::AlreadyExists, "destination already exists")),
+                Err(_) => fs::rename(&tmp_path, to_path),
+            },
+        }?;
+        // make the rename itself durable; not all platforms support this
+        let _ = File::open(dest_dir).and_then(|dir| dir.sync_all());
+        Ok(())
+    })();
+
+    if let Err(e) = copy_res {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    fs::remove_file(from_path)
+}
+
+/// This function checks whether files could be written into a directory,
+/// like [`test_write_to_dir`], but without creating anything.
+/// The check is best-effort, as it relies on permission bits only.
+///
+/// Returns whether the directory already exists.
+pub fn check_dir_writable(dir: &Path) -> std::io::Result<bool> {
+    use std::io::{Error, ErrorKind};
+
+    if dir.exists() {
+        let meta = std::fs::metadata(dir)?;
+        if !meta.is_dir() {
+            return Err(Error::new(ErrorKind::NotADirectory, "destination is not a directory"));
+        }
+        let _ = std::fs::read_dir(dir)?;
+        if meta.permissions().readonly() {
+            return Err(Error::new(ErrorKind::PermissionDenied, "destination is read-only"));
+        }
+        return Ok(true);
+    }
+
+    // the nearest existing ancestor must be a directory, for the rest to be created in
+    match dir.ancestors().skip(1).find(|ancestor| ancestor.exists()) {
+        Some(ancestor) if !ancestor.is_dir() => Err(Error::new(
+            ErrorKind::NotADirectory,
+            format!("{:?} is not a directory", ancestor),
+        )),
+        _ => Ok(false),
+    }
+}
+
+/// The kind of link to replace a duplicate with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    /// A hard link; only possible within the same filesystem.
+    Hard,
+    /// A relative symbolic link.
+    Symbolic,
+    /// A hard link where possible, otherwise a relative symbolic link.
+    Auto,
+}
+
+/// The outcome of replacing a file with a link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkOutcome {
+    /// The file was replaced with a hard link.
+    Hard,
+    /// The file was replaced with a symbolic link.
+    Symbolic,
+    /// The file already was a hard link to the target; nothing was changed.
+    Unchanged,
+}
+
+/// This function computes the path of `target` relative to the directory `base`.
+/// Both paths are expected to be absolute and canonical.
+fn relative_path(base: &Path, target: &Path) -> PathBuf {
+    let base: Vec<_> = base.components().collect();
+    let target: Vec<_> = target.components().collect();
+    let common_len = base.iter().zip(target.iter()).take_while(|(a, b)| a == b).count();
+    let ups = (common_len..base.len()).map(|_| std::path::Component::ParentDir);
+    ups.chain(target[common_len..].iter().copied()).collect()
+}
+
+/// This function computes what a symbolic link at `path` should contain
+/// to point to `target`, relative to the link's directory.
+pub fn symlink_target(path: &Path, target: &Path) -> std::io::Result<PathBuf> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    Ok(relative_path(&dir.canonicalize()?, &target.canonicalize()?))
+}
+
+/// Returns whether two paths refer to the same file on disk.
+#[cfg(unix)]
+pub fn is_same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+#[cfg(not(unix))]
+pub fn is_same_file(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Returns whether two paths are on the same filesystem.
+#[cfg(unix)]
+fn is_same_filesystem(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev()
+}
+#[cfg(not(unix))]
+fn is_same_filesystem(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> bool {
+    true
+}
+
+/// Creates a symbolic link at `path`, pointing to `link_target`.
+#[cfg(unix)]
+fn create_symlink(link_target: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(link_target, path)
+}
+#[cfg(not(unix))]
+fn create_symlink(_link_target: &Path, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "symbolic links are not supported on this platform",
+    ))
+}
+
+/// This function predicts the outcome of [`replace_with_link`],
+/// without modifying anything.
+pub fn predict_link(path: &Path, target: &Path, link_type: LinkType) -> std::io::Result<LinkOutcome> {
+    let (meta, target_meta) = (std::fs::metadata(path)?, std::fs::metadata(target)?);
+    let outcome = match link_type {
+        _ if is_same_file(&meta, &target_meta) => LinkOutcome::Unchanged,
+        LinkType::Hard => LinkOutcome::Hard,
+        LinkType::Symbolic => LinkOutcome::Symbolic,
+        LinkType::Auto if is_same_filesystem(&meta, &target_meta) => LinkOutcome::Hard,
+        LinkType::Auto => LinkOutcome::Symbolic,
+    };
+    Ok(outcome)
+}
+
+/// This function replaces a file with a link to `target`.
+///
+/// The link is first created under a temporary name next to the file,
+/// and then renamed over it, so that the file's path never stops resolving.
+/// Symbolic links are relative to the file's directory,
+/// so that moving the whole collection does not break them.
+pub fn replace_with_link(path: &Path, target: &Path, link_type: LinkType) -> std::io::Result<LinkOutcome> {
+    use std::{
+        fs::{self, hard_link},
+        io::{Error, ErrorKind},
+    };
+
+    if is_same_file(&fs::metadata(path)?, &fs::metadata(target)?) {
+        return Ok(LinkOutcome::Unchanged);
+    }
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let tmp_path = (0..)
+        .map(|n| dir.join(format!(".img-dedup-link-{}.tmp", n)))
+        .find(|path| fs::symlink_metadata(path).is_err())
+        .unwrap(); // will find one eventually
+
+    let symlink = || {
+        let link_target = symlink_target(path, target)?;
+        create_symlink(&link_target, &tmp_path).map(|_| LinkOutcome::Symbolic)
+    };
+    let outcome = match link_type {
+        LinkType::Hard => hard_link(target, &tmp_path).map(|_| LinkOutcome::Hard)?,
+        LinkType::Symbolic => symlink()?,
+        LinkType::Auto => match hard_link(target, &tmp_path) {
+            Ok(_) => LinkOutcome::Hard,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(e),
+            Err(_) => symlink()?,
+        },
+    };
+
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(Error::new(e.kind(), format!("cannot replace file with link: {}", e)));
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+    use std::fs;
+
+    /// Create two source files with the same name in different directories,
+    /// and a destination directory.
+    fn same_name_sources(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let dir = test_dir(name);
+        let (a, b, dest) = (dir.join("a/IMG.jpg"), dir.join("b/IMG.jpg"), dir.join("dest"));
+        for (path, content) in [(&a, "a"), (&b, "b")] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        fs::create_dir_all(&dest).unwrap();
+        (a, b, dest)
+    }
+
+    #[test]
+    fn resolve_suffix() {
+        let (a, b, dest) = same_name_sources("resolve-suffix");
+        fs::write(dest.join("IMG-1.jpg"), "existing").unwrap();
+        let mut claimed = HashSet::new();
+        let resolve = |path, claimed: &mut _| resolve_dest_path(path, &dest, CollisionStrategy::Suffix, claimed);
+
+        assert_eq!(resolve(&a, &mut claimed), Ok(Some(dest.join("IMG.jpg"))));
+        assert_eq!(resolve(&b, &mut claimed), Ok(Some(dest.join("IMG-2.jpg"))));
+        assert_eq!(claimed.len(), 2);
+    }
+
+    #[test]
+    fn resolve_hash() {
+        let (a, b, dest) = same_name_sources("resolve-hash");
+        let mut claimed = HashSet::new();
+        let resolve = |path, claimed: &mut _| resolve_dest_path(path, &dest, CollisionStrategy::Hash, claimed);
+
+        assert_eq!(resolve(&a, &mut claimed), Ok(Some(dest.join("IMG.jpg"))));
+        let checksum = file_checksum(&b).unwrap();
+        let expected = dest.join(format!("IMG-{}.jpg", &checksum[..8]));
+        assert_eq!(resolve(&b, &mut claimed), Ok(Some(expected)));
+    }
+
+    #[test]
+    fn resolve_skip_and_abort() {
+        let (a, b, dest) = same_name_sources("resolve-skip-abort");
+        let mut claimed = HashSet::new();
+        let first = resolve_dest_path(&a, &dest, CollisionStrategy::Skip, &mut claimed);
+        assert_eq!(first, Ok(Some(dest.join("IMG.jpg"))));
+        assert_eq!(
+            resolve_dest_path(&b, &dest, CollisionStrategy::Skip, &mut claimed),
+            Ok(None)
+        );
+        assert!(resolve_dest_path(&b, &dest, CollisionStrategy::Abort, &mut claimed).is_err());
+        assert_eq!(claimed.len(), 1);
+    }
+
+    #[test]
+    fn resolve_overwrite_never_replaces_same_batch() {
+        let (a, b, dest) = same_name_sources("resolve-overwrite");
+        fs::write(dest.join("IMG.jpg"), "existing").unwrap();
+        let mut claimed = HashSet::new();
+        let resolve = |path, claimed: &mut _| resolve_dest_path(path, &dest, CollisionStrategy::Overwrite, claimed);
+
+        // the file that existed before is replaced, but the first moved file is not
+        assert_eq!(resolve(&a, &mut claimed), Ok(Some(dest.join("IMG.jpg"))));
+        assert_eq!(resolve(&b, &mut claimed), Ok(Some(dest.join("IMG-1.jpg"))));
+    }
+
+    /// The names of all entries in a directory, sorted.
+    fn dir_entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|de| de.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_verify_delete_preserves_metadata() {
+        use std::{
+            fs::{File, FileTimes, Permissions},
+            os::unix::fs::PermissionsExt,
+            time::{Duration, SystemTime},
+        };
+
+        let dir = test_dir("copy-verify-delete");
+        let (src, dest) = (dir.join("a.jpg"), dir.join("dest/a.jpg"));
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        fs::write(&src, "content").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(&src)
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(mtime))
+            .unwrap();
+        fs::set_permissions(&src, Permissions::from_mode(0o640)).unwrap();
+
+        copy_verify_delete(&src, &dest, false).unwrap();
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "content");
+        let meta = fs::metadata(&dest).unwrap();
+        assert_eq!(meta.modified().unwrap(), mtime);
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+        // the temporary copy is gone
+        assert_eq!(dir_entries(dest.parent().unwrap()), vec!["a.jpg"]);
+    }
+
+    #[test]
+    fn copy_verify_delete_never_replaces_unless_overwriting() {
+        let dir = test_dir("copy-verify-delete-existing");
+        let (src, dest) = (dir.join("a.jpg"), dir.join("dest/a.jpg"));
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        fs::write(&src, "new").unwrap();
+        fs::write(&dest, "existing").unwrap();
+
+        let err = copy_verify_delete(&src, &dest, false).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&src).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "existing");
+        assert_eq!(dir_entries(dest.parent().unwrap()), vec!["a.jpg"]);
+
+        copy_verify_delete(&src, &dest, true).unwrap();
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+    }
+
+    /// Create a keeper and a duplicate with the same content in different directories.
+    fn keeper_and_duplicate(name: &str) -> (PathBuf, PathBuf) {
+        let dir = test_dir(name);
+        let (keeper, dup) = (dir.join("keep/IMG.jpg"), dir.join("dups/nested/IMG.jpg"));
+        for path in [&keeper, &dup] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "content").unwrap();
+        }
+        (keeper, dup)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_hard() {
+        let (keeper, dup) = keeper_and_duplicate("link-hard");
+        assert_eq!(predict_link(&dup, &keeper, LinkType::Auto).unwrap(), LinkOutcome::Hard);
+        assert_eq!(
+            replace_with_link(&dup, &keeper, LinkType::Hard).unwrap(),
+            LinkOutcome::Hard
+        );
+        assert!(is_same_file(
+            &fs::metadata(&dup).unwrap(),
+            &fs::metadata(&keeper).unwrap()
+        ));
+        assert_eq!(dir_entries(dup.parent().unwrap()), vec!["IMG.jpg"]);
+
+        // linking again changes nothing
+        assert_eq!(
+            predict_link(&dup, &keeper, LinkType::Hard).unwrap(),
+            LinkOutcome::Unchanged
+        );
+        assert_eq!(
+            replace_with_link(&dup, &keeper, LinkType::Hard).unwrap(),
+            LinkOutcome::Unchanged
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_symbolic_is_relative() {
+        let (keeper, dup) = keeper_and_duplicate("link-symbolic");
+        assert_eq!(
+            predict_link(&dup, &keeper, LinkType::Symbolic).unwrap(),
+            LinkOutcome::Symbolic
+        );
+        assert_eq!(
+            replace_with_link(&dup, &keeper, LinkType::Symbolic).unwrap(),
+            LinkOutcome::Symbolic
+        );
+        assert_eq!(fs::read_link(&dup).unwrap(), Path::new("../../keep/IMG.jpg"));
+        assert_eq!(fs::read_to_string(&dup).unwrap(), "content");
+        assert_eq!(dir_entries(dup.parent().unwrap()), vec!["IMG.jpg"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_removes_temporary_link_on_failure() {
+        let (keeper, dup) = keeper_and_duplicate("link-failure");
+        // a directory cannot be replaced with a link
+        fs::remove_file(&dup).unwrap();
+        fs::create_dir_all(dup.join("inner")).unwrap();
+
+        for link_type in [LinkType::Hard, LinkType::Symbolic] {
+            let err = replace_with_link(&dup, &keeper, link_type).unwrap_err();
+            assert!(err.to_string().starts_with("cannot replace file with link"), "{}", err);
+            assert_eq!(dir_entries(dup.parent().unwrap()), vec!["IMG.jpg"]);
+        }
+        assert!(dup.join("inner").is_dir());
+    }
+}
@@ -0,0 +1,169 @@
+//! This module implements the journal of file moves,
+//! which records where each file came from so that moves can be undone.
+//!
+//! The journal is a JSON Lines file with one entry per moved file,
+//! appended to (and flushed) immediately after each move.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A single recorded move.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// When the move happened, in RFC 3339 format.
+    pub timestamp: String,
+    /// The original path of the file.
+    pub source: PathBuf,
+    /// The path the file was moved to.
+    pub destination: PathBuf,
+    /// The ID of the duplicate group the file belonged to.
+    pub group: usize,
+    /// The hamming distance from the file to its group's keeper.
+    pub distance: u32,
+}
+impl JournalEntry {
+    /// Create an entry timestamped with the current local time.
+    ///
+    /// Paths are made absolute, so that the journal
+    /// does not depend on the working directory.
+    pub fn now(source: &Path, destination: &Path, group: usize, distance: u32) -> Self {
+        let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        Self {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            source: absolute(source),
+            destination: absolute(destination),
+            group,
+            distance,
+        }
+    }
+}
+
+/// An open journal file that entries are appended to.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+impl Journal {
+    /// Open a journal for appending, creating it if it does not exist.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// The path of the journal file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an entry and flush it to disk immediately,
+    /// so that the journal stays accurate even if interrupted.
+    pub fn record(&mut self, entry: &JournalEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()
+    }
+}
+
+/// This function reads all entries of a journal, in the order they were recorded.
+pub fn read_journal(path: &Path) -> Result<Vec<JournalEntry>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+        .map(|(n, line)| {
+            let line = line.map_err(|e| e.to_string())?;
+            serde_json::from_str(&line).map_err(|e| format!("Line {}: {}", n + 1, e))
+        })
+        .collect()
+}
+
+/// This function replaces the content of a journal with the given entries.
+/// If there are no entries, the journal is deleted instead.
+pub fn rewrite_journal(path: &Path, entries: &[JournalEntry]) -> std::io::Result<()> {
+    if entries.is_empty() {
+        return fs::remove_file(path);
+    }
+
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for entry in entries.iter() {
+        serde_json::to_writer(&mut writer, entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn record_and_read() {
+        let dir = test_dir("journal-round-trip");
+        let path = dir.join("moves.jsonl");
+        let entries = vec![
+            JournalEntry::now(&dir.join("a.jpg"), &dir.join("dups/a.jpg"), 1, 0),
+            JournalEntry::now(Path::new("rel/b\n\"c\".jpg"), &dir.join("dups/b.jpg"), 2, 3),
+        ];
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.record(&entries[0]).unwrap();
+        // reopening appends to the existing journal
+        let mut journal = Journal::open(&path).unwrap();
+        journal.record(&entries[1]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+
+        let read = read_journal(&path).unwrap();
+        assert_eq!(read, entries);
+        assert!(read[1].source.is_absolute());
+    }
+
+    #[test]
+    fn read_skips_blank_lines_and_reports_bad_ones() {
+        let dir = test_dir("journal-bad-line");
+        let path = dir.join("moves.jsonl");
+        let entry = JournalEntry::now(&dir.join("a.jpg"), &dir.join("dups/a.jpg"), 1, 0);
+        let line = serde_json::to_string(&entry).unwrap();
+
+        fs::write(&path, format!("{}\n\n{}\n", line, line)).unwrap();
+        assert_eq!(read_journal(&path).unwrap(), vec![entry.clone(), entry]);
+
+        fs::write(&path, format!("{}\n{{\"source\": \n", line)).unwrap();
+        let err = read_journal(&path).unwrap_err();
+        assert!(err.starts_with("Line 2: "), "{}", err);
+    }
+
+    #[test]
+    fn rewrite_and_remove() {
+        let dir = test_dir("journal-rewrite");
+        let path = dir.join("moves.jsonl");
+        let entries: Vec<_> = (0..3)
+            .map(|n| JournalEntry::now(&dir.join(format!("{}.jpg", n)), &dir.join("dups"), n, 0))
+            .collect();
+        let mut journal = Journal::open(&path).unwrap();
+        entries.iter().for_each(|entry| journal.record(entry).unwrap());
+
+        rewrite_journal(&path, &entries[1..]).unwrap();
+        assert_eq!(read_journal(&path).unwrap(), &entries[1..]);
+
+        // an empty journal is deleted
+        rewrite_journal(&path, &[]).unwrap();
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,327 @@
+//! This module decides which member of a duplicate group is the original
+//! that should be kept, while the others are acted upon.
+
+use std::{
+    cell::OnceCell,
+    cmp::Ordering,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use image::ImageFormat;
+use regex::Regex;
+
+use crate::cluster::DupGroup;
+
+/// Information about a group member that keeper rules are evaluated on.
+///
+/// Any information that cannot be determined is `None`,
+/// and is considered worse than any known value.
+#[derive(Debug, Clone)]
+pub struct Candidate<'a> {
+    pub path: &'a Path,
+    /// The absolute path, used for matching path rules.
+    pub abs_path: PathBuf,
+    pub dimensions: Option<(u32, u32)>,
+    pub file_size: Option<u64>,
+    pub mtime: Option<SystemTime>,
+    pub format: Option<ImageFormat>,
+    /// Whether the file has EXIF metadata, only read when first needed.
+    has_exif: OnceCell<Option<bool>>,
+}
+impl<'a> Candidate<'a> {
+    /// Gather information about a file. Only the image header is read;
+    /// EXIF metadata is read later, if a rule asks for it.
+    pub fn of(path: &'a Path) -> Self {
+        let metadata = fs::metadata(path).ok();
+        Self {
+            path,
+            abs_path: path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+            dimensions: image::image_dimensions(path).ok(),
+            file_size: metadata.as_ref().map(|m| m.len()),
+            mtime: metadata.and_then(|m| m.modified().ok()),
+            format: ImageFormat::from_path(path).ok(),
+            has_exif: OnceCell::new(),
+        }
+    }
+
+    fn has_exif(&self) -> Option<bool> {
+        *self.has_exif.get_or_init(|| {
+            File::open(self.path).ok().map(|file| {
+                exif::Reader::new()
+                    .read_from_container(&mut BufReader::new(file))
+                    .is_ok()
+            })
+        })
+    }
+
+    fn file_name(&self) -> &str {
+        self.path.file_name().and_then(|name| name.to_str()).unwrap_or("")
+    }
+
+    fn is_lossless(&self) -> Option<bool> {
+        use ImageFormat::*;
+        self.format
+            .map(|format| matches!(format, Png | Gif | Bmp | Tiff | Tga | Pnm | Ico | Farbfeld))
+    }
+
+    fn pixel_count(&self) -> Option<u64> {
+        self.dimensions.map(|(w, h)| u64::from(w) * u64::from(h))
+    }
+}
+
+/// A criterion for preferring one group member over another as the keeper.
+#[derive(Debug, Clone)]
+pub enum KeepRule {
+    /// Prefer the member with the most pixels.
+    HighestResolution,
+    /// Prefer the member with the largest file size.
+    LargestFile,
+    /// Prefer the member with the oldest modification time.
+    Oldest,
+    /// Prefer the member with the shortest path.
+    ShortestPath,
+    /// Prefer members of the specified format.
+    PreferFormat(ImageFormat),
+    /// Prefer members in a lossless format.
+    PreferLossless,
+    /// Prefer members with EXIF metadata.
+    PreferExif,
+    /// Prefer members whose absolute path matches.
+    PreferPath(Regex),
+    /// Avoid members whose absolute path matches.
+    AvoidPath(Regex),
+    /// Prefer members whose file name matches.
+    PreferName(Regex),
+    /// Avoid members whose file name matches.
+    AvoidName(Regex),
+}
+impl KeepRule {
+    /// Compare two candidates. `Ordering::Less` means `a` is preferred over `b`.
+    pub fn compare(&self, a: &Candidate, b: &Candidate) -> Ordering {
+        /// Prefer the larger known value. Unknown values are the least preferred.
+        fn prefer_larger<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+            b.cmp(&a) // None < Some
+        }
+        /// Prefer the smaller known value. Unknown values are the least preferred.
+        fn prefer_smaller<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+            match (a, b) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            }
+        }
+
+        use KeepRule::*;
+        match self {
+            HighestResolution => prefer_larger(a.pixel_count(), b.pixel_count()),
+            LargestFile => prefer_larger(a.file_size, b.file_size),
+            Oldest => prefer_smaller(a.mtime, b.mtime),
+            ShortestPath => prefer_smaller(Some(a.path.as_os_str().len()), Some(b.path.as_os_str().len())),
+            PreferFormat(format) => prefer_larger(Some(a.format == Some(*format)), Some(b.format == Some(*format))),
+            PreferLossless => prefer_larger(a.is_lossless(), b.is_lossless()),
+            PreferExif => prefer_larger(a.has_exif(), b.has_exif()),
+            PreferPath(re) => prefer_larger(Some(is_path_match(re, a)), Some(is_path_match(re, b))),
+            AvoidPath(re) => prefer_smaller(Some(is_path_match(re, a)), Some(is_path_match(re, b))),
+            PreferName(re) => prefer_larger(Some(re.is_match(a.file_name())), Some(re.is_match(b.file_name()))),
+            AvoidName(re) => prefer_smaller(Some(re.is_match(a.file_name())), Some(re.is_match(b.file_name()))),
+        }
+    }
+
+    /// A short human-readable description.
+    pub fn describe(&self) -> String {
+        use KeepRule::*;
+        match self {
+            HighestResolution => "highest resolution".to_string(),
+            LargestFile => "largest file".to_string(),
+            Oldest => "oldest modification time".to_string(),
+            ShortestPath => "shortest path".to_string(),
+            PreferFormat(format) => format!("preferred format {:?}", format),
+            PreferLossless => "lossless format".to_string(),
+            PreferExif => "presence of EXIF metadata".to_string(),
+            PreferPath(re) => format!("path matching /{}/", re),
+            AvoidPath(re) => format!("path not matching /{}/", re),
+            PreferName(re) => format!("name matching /{}/", re),
+            AvoidName(re) => format!("name not matching /{}/", re),
+        }
+    }
+}
+
+fn is_path_match(re: &Regex, candidate: &Candidate) -> bool {
+    candidate.abs_path.to_str().is_some_and(|path| re.is_match(path))
+}
+
+/// This function chooses the keeper of a group according to an ordered
+/// list of rules. Each rule is only consulted if all previous rules
+/// consider the members equally preferable.
+///
+/// If all rules are tied, the member with the
+/// lexicographically smallest path is chosen.
+///
+/// Returns the index of the keeper in the group's members,
+/// and the rule that set the keeper apart from the runner-up
+/// (`None` if all rules were tied, or if there is no runner-up).
+pub fn choose_keeper<'r>(group: &DupGroup, rules: &'r [KeepRule]) -> (usize, Option<&'r KeepRule>) {
+    let candidates: Vec<_> = group.members.iter().map(|&(path, _)| Candidate::of(path)).collect();
+    let compare_all = |a: &Candidate, b: &Candidate| {
+        rules
+            .iter()
+            .map(|rule| rule.compare(a, b))
+            .find(|&ord| ord != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    };
+
+    let mut ranking: Vec<_> = (0..candidates.len()).collect();
+    ranking.sort_by(|&i, &j| {
+        let (a, b) = (&candidates[i], &candidates[j]);
+        compare_all(a, b).then_with(|| a.path.cmp(b.path))
+    });
+
+    let keeper = ranking[0];
+    let decisive_rule = ranking.get(1).and_then(|&runner_up| {
+        rules
+            .iter()
+            .find(|rule| rule.compare(&candidates[keeper], &candidates[runner_up]) != Ordering::Equal)
+    });
+    (keeper, decisive_rule)
+}
+
+/// A group member that is not its group's keeper,
+/// and is therefore to be acted upon.
+#[derive(Debug, Clone, Copy)]
+pub struct Duplicate<'a> {
+    pub path: &'a Path,
+    /// The keeper of the group this duplicate belongs to.
+    pub keeper: &'a Path,
+    /// The ID of the group, as shown to the user (starting from 1).
+    pub group_id: usize,
+    /// The hamming distance to the keeper.
+    pub distance: u32,
+}
+
+/// This function lists all group members except for the keepers,
+/// sorted by their paths.
+pub fn list_duplicates<'a>(groups: &[DupGroup<'a>], keepers: &[usize]) -> Vec<Duplicate<'a>> {
+    let mut duplicates: Vec<_> = groups
+        .iter()
+        .zip(keepers.iter())
+        .enumerate()
+        .flat_map(|(id, (group, &keeper))| {
+            (0..group.members.len())
+                .filter(move |&n| n != keeper)
+                .map(move |n| Duplicate {
+                    path: group.members[n].0,
+                    keeper: group.members[keeper].0,
+                    group_id: id + 1,
+                    distance: group.dist(n, keeper),
+                })
+        })
+        .collect();
+    duplicates.sort_by_key(|dup| dup.path);
+    duplicates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use img_hash::ImageHash;
+    use std::time::Duration;
+
+    /// A candidate that does not exist on disk, with the specified properties.
+    fn candidate(path: &str, dimensions: (u32, u32), file_size: u64, age_secs: u64) -> Candidate<'_> {
+        Candidate {
+            path: Path::new(path),
+            abs_path: PathBuf::from("/photos").join(path),
+            dimensions: Some(dimensions),
+            file_size: Some(file_size),
+            mtime: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 - age_secs)),
+            format: ImageFormat::from_path(path).ok(),
+            has_exif: OnceCell::from(Some(path.ends_with(".jpg"))),
+        }
+    }
+
+    #[test]
+    fn compare_rules() {
+        use KeepRule::*;
+        let jpg = candidate("a/IMG_1.jpg", (200, 100), 300, 10);
+        let png = candidate("backup/IMG_1.png", (100, 100), 900, 20);
+
+        let cases = [
+            (HighestResolution, Ordering::Less),
+            (LargestFile, Ordering::Greater),
+            (Oldest, Ordering::Greater),
+            (ShortestPath, Ordering::Less),
+            (PreferFormat(ImageFormat::Png), Ordering::Greater),
+            (PreferLossless, Ordering::Greater),
+            (PreferExif, Ordering::Less),
+            (PreferPath(Regex::new("^/photos/backup/").unwrap()), Ordering::Greater),
+            (AvoidPath(Regex::new("/backup/").unwrap()), Ordering::Less),
+            (PreferName(Regex::new(r"\.png$").unwrap()), Ordering::Greater),
+            (AvoidName(Regex::new("^IMG").unwrap()), Ordering::Equal),
+        ];
+        for (rule, expected) in cases.iter() {
+            assert_eq!(rule.compare(&jpg, &png), *expected, "{}", rule.describe());
+            assert_eq!(rule.compare(&png, &jpg), expected.reverse(), "{}", rule.describe());
+        }
+    }
+
+    #[test]
+    fn unknown_values_are_least_preferred() {
+        let known = candidate("a.jpg", (1, 1), 1, 0);
+        let unknown = Candidate {
+            dimensions: None,
+            file_size: None,
+            mtime: None,
+            ..candidate("b.jpg", (1, 1), 1, 0)
+        };
+        for rule in [KeepRule::HighestResolution, KeepRule::LargestFile, KeepRule::Oldest] {
+            assert_eq!(rule.compare(&known, &unknown), Ordering::Less, "{}", rule.describe());
+        }
+    }
+
+    #[test]
+    fn exif_is_read_lazily() {
+        let candidate = Candidate::of(Path::new("does-not-exist.jpg"));
+        assert!(candidate.has_exif.get().is_none());
+        assert_eq!(candidate.has_exif(), None);
+        assert_eq!(candidate.has_exif.get(), Some(&None));
+    }
+
+    /// A group of identical images.
+    fn group_of<'a>(paths: &[&'a Path], hash: &'a ImageHash) -> DupGroup<'a> {
+        DupGroup {
+            members: paths.iter().map(|&path| (path, 0)).collect(),
+            hashes: vec![hash; paths.len()],
+        }
+    }
+
+    #[test]
+    fn choose_keeper_reports_decisive_rule() {
+        use crate::test_util::test_dir;
+
+        let dir = test_dir("keeper-choose");
+        let (big, small, other) = (dir.join("b.bin"), dir.join("a.bin"), dir.join("c.bin"));
+        for (path, size) in [(&big, 20), (&small, 10), (&other, 10)] {
+            fs::write(path, vec![0; size]).unwrap();
+        }
+        let hash = ImageHash::from_bytes(&[0; 8]).unwrap();
+        let group = group_of(&[&big, &small, &other], &hash);
+        let rules = [KeepRule::ShortestPath, KeepRule::LargestFile];
+
+        // paths of equal length: the file size decides
+        let (keeper, decisive) = choose_keeper(&group, &rules);
+        assert_eq!(group.members[keeper].0, big.as_path());
+        assert!(matches!(decisive, Some(KeepRule::LargestFile)));
+
+        // all rules tied: the smallest path is kept
+        let group = group_of(&[&other, &small], &hash);
+        let (keeper, decisive) = choose_keeper(&group, &rules);
+        assert_eq!(group.members[keeper].0, small.as_path());
+        assert!(decisive.is_none());
+
+        // no runner-up
+        let group = group_of(&[&other], &hash);
+        assert!(matches!(choose_keeper(&group, &rules), (0, None)));
+    }
+}
@@ -1,32 +1,86 @@
+mod banding;
+mod bk_tree;
+mod cache;
 mod clap_def;
 mod cli_helper;
+mod cluster;
 mod compute;
 mod io;
+mod journal;
+mod keeper;
+mod output;
+mod plan;
+mod preview;
+#[cfg(target_os = "linux")]
+mod reflink;
+mod report;
+mod review;
+mod script;
 mod sub_cmds;
 mod sub_ops;
+#[cfg(test)]
+mod test_util;
+mod trash;
 
 use crossbeam_channel::bounded;
+use itertools::Itertools;
 use regex::Regex;
-use std::{fs::read_dir, path::Path, process::exit, thread, time::Duration};
+use std::{
+    fs::{read_dir, File},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
 
 use crate::{
+    cache::HashCache,
     clap_def::build_app,
-    io::load_in,
-    sub_cmds::{hash_once, move_duplicates, scan_duplicates},
+    cli_helper::parse_hash_size,
+    io::{load_in, WalkOptions},
+    sub_cmds::{
+        apply, delete_duplicates, hash_once, link_duplicates, move_duplicates, review_duplicates, scan_duplicates, undo,
+    },
 };
 
+#[cfg(target_os = "linux")]
+use crate::sub_cmds::reflink_duplicates;
+
 fn main() {
     let clap_matches = build_app().get_matches();
 
+    // dispatch subcmds that do not scan any input
+    match clap_matches.subcommand() {
+        ("apply", Some(sub_matches)) => return apply(sub_matches),
+        ("undo", Some(sub_matches)) => return undo(sub_matches),
+        _ => {}
+    }
+
     // create single-producer, multiple-consumer channel
     let (imgs_tx, imgs_rx) = bounded(128);
 
     // get input options
-    let in_dir = clap_matches.value_of("input_dir").unwrap(); // arg is required
+    let in_roots: Vec<PathBuf> = match clap_matches.values_of_os("inputs") {
+        // individual files may have non-UTF-8 names, which are rejected when loading
+        Some(inputs) => inputs.map(PathBuf::from).collect(),
+        // cannot be required by clap, because some subcmds take no input
+        None => clap::Error::with_description(
+            "At least one input is required for this subcommand",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
     let in_filter_regex = Regex::new(
         clap_matches.value_of("input_filter").unwrap(), // default provided by clap
     )
     .unwrap(); // regex validated by clap
+    let walk_opts = WalkOptions {
+        max_depth: clap_matches
+            .value_of("max_depth")
+            .map(|depth| depth.parse::<usize>().unwrap()), // usize parse validated by clap
+        follow_symlinks: clap_matches.is_present("follow_symlinks"),
+    };
 
     // get concurrency options
     let concurrency = clap_matches
@@ -35,20 +89,58 @@ fn main() {
         .parse::<usize>()
         .unwrap(); // usize parse validated by clap
 
-    // opening imgs_dir outside of thread makes for easier code logic
-    let opened_imgs_dir = read_dir(Path::new(in_dir)).unwrap_or_else(|e| {
-        println!("Failed to open the input directory: {:?}", e);
-        exit(1);
-    });
+    // get cache options
+    // all remaining subcommands hash, so their args are always available
+    let (_, sub_matches) = clap_matches.subcommand();
+    let sub_matches = sub_matches.unwrap(); // subcommand is required
+    let cache = match clap_matches.is_present("no_cache") {
+        true => None,
+        false => {
+            let cache_path = Path::new(clap_matches.value_of("cache").unwrap()); // default provided by clap
+            let algorithm = sub_matches.value_of("algorithm").unwrap(); // default provided by clap
+            let hash_size = parse_hash_size(sub_matches.value_of("hash-size").unwrap()).unwrap(); // validated by clap
+            let rebuild = clap_matches.is_present("rebuild_cache");
+            match HashCache::load(cache_path, algorithm, hash_size, rebuild) {
+                Ok(cache) => {
+                    eprintln!("Using hash cache at [{}]", cache_path.display());
+                    Some(Arc::new(Mutex::new(cache)))
+                }
+                Err(e) => {
+                    eprintln!("Failed to load hash cache, continuing without it: {}", e);
+                    None
+                }
+            }
+        }
+    };
+
+    // checking input roots outside of thread makes for easier code logic
+    for root in in_roots.iter() {
+        let check_res = match root.is_dir() {
+            true => read_dir(root).map(|_| ()),
+            false => File::open(root).map(|_| ()),
+        };
+        if let Err(e) = check_res {
+            eprintln!("Failed to open the input {:?}: {:?}", root, e);
+            exit(1);
+        }
+    }
 
     // start imgs loading (single producer)
-    println!(
-        "Loading files in [{}] with regex filter [/{}/]...",
-        in_dir,
+    eprintln!(
+        "Loading files in {} with regex filter [/{}/]...",
+        in_roots.iter().map(|root| format!("[{}]", root.display())).join(", "),
         in_filter_regex.as_str()
     );
+    let in_roots_local = in_roots.clone();
+    let cache_local = cache.clone();
     thread::spawn(move || {
-        load_in(imgs_tx, opened_imgs_dir, &in_filter_regex);
+        load_in(
+            imgs_tx,
+            &in_roots_local,
+            walk_opts,
+            &in_filter_regex,
+            cache_local.as_deref(),
+        );
     });
 
     // spawn image loader monitor daemon
@@ -57,14 +149,14 @@ fn main() {
     thread::spawn(move || 'thread: loop {
         let queue_len = imgs_rx_monitor.len();
         if queue_len > 0 {
-            println!(
+            eprintln!(
                 "IO loading images faster than we can hash; currently {} in queue",
                 queue_len
             );
         }
         // sleep for 5s total, but check for termination every 100ms
         for _ in 0..50 {
-            if let Ok(_) = monitor_kill_rx.try_recv() {
+            if monitor_kill_rx.try_recv().is_ok() {
                 break 'thread;
             }
             thread::sleep(Duration::from_millis(100));
@@ -72,18 +164,31 @@ fn main() {
     });
 
     // log concurrency info
-    println!("Using up to {} threads", concurrency);
+    eprintln!("Using up to {} threads", concurrency);
 
     // dispatch task to subcmds
     match clap_matches.subcommand() {
         ("hash", Some(sub_matches)) => {
-            let _ = hash_once(imgs_rx, concurrency, sub_matches);
+            let _ = hash_once(imgs_rx, concurrency, cache.as_deref(), &in_roots, sub_matches);
         }
         ("scan-duplicates", Some(sub_matches)) => {
-            let _ = scan_duplicates(imgs_rx, concurrency, sub_matches);
+            let _ = scan_duplicates(imgs_rx, concurrency, cache.as_deref(), &in_roots, sub_matches);
         }
         ("move-duplicates", Some(sub_matches)) => {
-            move_duplicates(imgs_rx, concurrency, sub_matches);
+            move_duplicates(imgs_rx, concurrency, cache.as_deref(), &in_roots, sub_matches);
+        }
+        ("delete-duplicates", Some(sub_matches)) => {
+            delete_duplicates(imgs_rx, concurrency, cache.as_deref(), &in_roots, sub_matches);
+        }
+        ("link-duplicates", Some(sub_matches)) => {
+            link_duplicates(imgs_rx, concurrency, cache.as_deref(), &in_roots, sub_matches);
+        }
+        ("review", Some(sub_matches)) => {
+            review_duplicates(imgs_rx, concurrency, cache.as_deref(), &in_roots, sub_matches);
+        }
+        #[cfg(target_os = "linux")]
+        ("reflink-duplicates", Some(sub_matches)) => {
+            reflink_duplicates(imgs_rx, concurrency, cache.as_deref(), &in_roots, sub_matches);
         }
         _ => unreachable!("Cases should always cover all defined subcmds"),
     };
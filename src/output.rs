@@ -0,0 +1,613 @@
+//! This module defines the machine-readable output formats
+//! of the `hash` and `scan-duplicates` subcommands.
+//!
+//! Each result is one flat record (an image, a similar pair,
+//! or a member of a duplicate group), so that all formats share the same fields.
+//! The only exceptions are the formats of other duplicate finders
+//! (fdupes and rmlint), which follow their own layouts.
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Write},
+    path::Path,
+};
+
+use itertools::Itertools;
+use serde::Serialize;
+
+/// The format in which results are written to stdout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable text, as logged to the console.
+    Text,
+    /// A single JSON document, with the hash settings and an array of records.
+    Json,
+    /// One self-contained JSON object per line, including the hash settings.
+    Ndjson,
+    /// Comma-separated values, with a header row.
+    Csv,
+    /// Tab-separated values, with a header row.
+    Tsv,
+    /// A user-defined template, rendered once per record.
+    Template(Template),
+    /// fdupes' format: one path per line, with groups separated by blank lines.
+    Fdupes,
+    /// rmlint's JSON format: an array of a header, one object per file, and a footer.
+    Rmlint,
+}
+
+/// A piece of a user-defined output template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplatePart {
+    Literal(String),
+    /// The name of a record field, e.g. `path` for `{path}`.
+    Placeholder(String),
+}
+
+/// A user-defined output template, e.g. `{path0}\t{path1}\t{dist}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(pub Vec<TemplatePart>);
+impl Template {
+    /// Look up the value of a placeholder in a record.
+    ///
+    /// Besides the record's own fields (and the hash settings),
+    /// `dist` is accepted as an alias of `distance`,
+    /// and `name`, `name0` and `name1` are the file names of the corresponding paths.
+    fn lookup(fields: &serde_json::Value, placeholder: &str) -> Option<String> {
+        use serde_json::Value;
+
+        let (key, file_name_only) = match placeholder {
+            "dist" => ("distance", false),
+            "name" => ("path", true),
+            "name0" => ("path0", true),
+            "name1" => ("path1", true),
+            key => (key, false),
+        };
+        let text = match fields.get(key)? {
+            Value::Null => String::new(),
+            Value::String(s) => s.clone(),
+            Value::Array(values) => values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(","),
+            other => other.to_string(),
+        };
+        match file_name_only {
+            true => Some(
+                Path::new(&text)
+                    .file_name()
+                    .map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+            ),
+            false => Some(text),
+        }
+    }
+
+    /// Render the template for a record.
+    ///
+    /// Returns Err with the name of the first unknown placeholder.
+    fn render(&self, fields: &serde_json::Value) -> Result<String, String> {
+        self.0
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(text) => Ok(text.clone()),
+                TemplatePart::Placeholder(name) => Self::lookup(fields, name).ok_or_else(|| name.clone()),
+            })
+            .collect()
+    }
+}
+
+/// The settings the hashes were computed with.
+#[derive(Debug, Clone, Serialize)]
+pub struct HashSettings<'a> {
+    pub algorithm: &'a str,
+    pub hash_size: (u32, u32),
+}
+
+/// The size and dimensions of an image file.
+/// Each is absent if it cannot be read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileStats {
+    pub size: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+impl FileStats {
+    /// Read the stats of a file, only decoding the image's header.
+    pub fn of(path: &Path) -> Self {
+        let size = std::fs::metadata(path).ok().map(|meta| meta.len());
+        let (width, height) = image::image_dimensions(path).ok().unzip();
+        Self { size, width, height }
+    }
+}
+
+// CSV does not support nested fields, so each record lists its file stats individually
+
+/// A hashed image, output by `hash`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HashRecord {
+    pub path: String,
+    /// The input root (directory or file) the image was loaded from.
+    pub root: Option<String>,
+    pub size: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub hash: String,
+}
+
+/// A pair of similar images, output by `scan-duplicates --view pairs`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PairRecord {
+    pub path0: String,
+    pub root0: Option<String>,
+    pub size0: Option<u64>,
+    pub width0: Option<u32>,
+    pub height0: Option<u32>,
+    pub hash0: String,
+    pub path1: String,
+    pub root1: Option<String>,
+    pub size1: Option<u64>,
+    pub width1: Option<u32>,
+    pub height1: Option<u32>,
+    pub hash1: String,
+    pub distance: u32,
+}
+
+/// A member of a duplicate group, output by `scan-duplicates --view groups`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GroupMemberRecord {
+    /// The ID of the group, as shown to the user (starting from 1).
+    pub group: usize,
+    pub path: String,
+    /// The input root (directory or file) the image was loaded from.
+    pub root: Option<String>,
+    pub size: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub hash: String,
+    /// The hamming distance to the group's representative.
+    pub distance: u32,
+    pub representative: bool,
+}
+
+/// A file of a duplicate group, in rmlint's JSON format.
+#[derive(Debug, Clone, Serialize)]
+pub struct RmlintFile {
+    pub id: usize,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// The percentage of files listed so far.
+    pub progress: u32,
+    /// Shared by all members of a group, since rmlint groups files by checksum.
+    pub checksum: String,
+    pub path: String,
+    pub size: u64,
+    /// The number of components of the absolute path.
+    pub depth: usize,
+    pub inode: Option<u64>,
+    pub disk_id: Option<u64>,
+    /// Whether this is the group's keeper.
+    pub is_original: bool,
+    /// The modification time, in seconds since the Unix epoch.
+    pub mtime: f64,
+}
+impl RmlintFile {
+    /// Describe a group member, reading its metadata from the filesystem.
+    ///
+    /// `id` and `progress` are left to be filled in when the whole list is known.
+    ///
+    /// Returns Err if the path is not valid UTF-8 (see [`full_path`]).
+    pub fn of(path: &Path, checksum: &str, is_original: bool) -> Result<Self, String> {
+        let metadata = std::fs::metadata(path).ok();
+        let (inode, disk_id) = match &metadata {
+            #[cfg(unix)]
+            Some(meta) => {
+                use std::os::unix::fs::MetadataExt;
+                (Some(meta.ino()), Some(meta.dev()))
+            }
+            _ => (None, None),
+        };
+        let path = full_path(path)?;
+        Ok(Self {
+            id: 0,
+            kind: "duplicate_file",
+            progress: 0,
+            checksum: checksum.to_string(),
+            depth: Path::new(&path).components().count() - 1, // exclude the root directory
+            path,
+            size: metadata.as_ref().map_or(0, |meta| meta.len()),
+            inode,
+            disk_id,
+            is_original,
+            mtime: metadata
+                .and_then(|meta| meta.modified().ok())
+                .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0.0, |mtime| mtime.as_secs_f64()),
+        })
+    }
+}
+
+/// Formats a path as an absolute path, for consumption by other programs.
+///
+/// Returns Err if the path is not valid UTF-8, rather than replacing
+/// the invalid bytes, which would make other programs act on a path that does not exist.
+pub fn full_path(path: &Path) -> Result<String, String> {
+    let abs_path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    match abs_path.into_os_string().into_string() {
+        Ok(path_str) => Ok(path_str),
+        Err(_) => Err(format!("Path is not a valid utf-8 string: {:?}", path)),
+    }
+}
+
+/// This function writes a list of records in a machine-readable format.
+///
+/// In JSON, the records are listed under the key `kind`,
+/// next to the hash settings. In NDJSON, the hash settings
+/// are repeated on every line instead. CSV and TSV only contain the records,
+/// with fields quoted as necessary (e.g. paths containing commas or newlines),
+/// after a header row that is written even if there are no records.
+/// A template is rendered once per line, and can refer to any field of a JSON line.
+pub fn write_records<R: Serialize + Default>(
+    out: &mut impl Write,
+    format: &OutputFormat,
+    settings: &HashSettings,
+    kind: &str,
+    records: &[R],
+) -> std::io::Result<()> {
+    #[derive(Serialize)]
+    struct Document<'a, R> {
+        #[serde(flatten)]
+        settings: &'a HashSettings<'a>,
+        #[serde(flatten)]
+        records: HashMap<&'a str, &'a [R]>,
+    }
+    #[derive(Serialize)]
+    struct Line<'a, R> {
+        #[serde(flatten)]
+        settings: &'a HashSettings<'a>,
+        #[serde(flatten)]
+        record: &'a R,
+    }
+
+    match format {
+        OutputFormat::Json => {
+            let records = HashMap::from([(kind, records)]);
+            serde_json::to_writer_pretty(&mut *out, &Document { settings, records })?;
+            writeln!(out)?;
+        }
+        OutputFormat::Ndjson => {
+            for record in records.iter() {
+                serde_json::to_writer(&mut *out, &Line { settings, record })?;
+                writeln!(out)?;
+            }
+        }
+        OutputFormat::Csv | OutputFormat::Tsv => {
+            let delimiter = match format {
+                OutputFormat::Tsv => b'\t',
+                _ => b',',
+            };
+            let mut writer = csv::WriterBuilder::new().delimiter(delimiter).from_writer(&mut *out);
+            if records.is_empty() {
+                // the header is only written before the first record, so take it from a default record
+                let mut sample = csv::WriterBuilder::new().delimiter(delimiter).from_writer(vec![]);
+                sample.serialize(R::default())?;
+                let sample = sample.into_inner().map_err(|e| e.into_error())?;
+                let mut reader = csv::ReaderBuilder::new()
+                    .delimiter(delimiter)
+                    .from_reader(sample.as_slice());
+                writer.write_record(reader.headers()?)?;
+            }
+            for record in records.iter() {
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Template(template) => {
+            // check placeholders before writing anything
+            let sample = serde_json::to_value(Line {
+                settings,
+                record: &R::default(),
+            })?;
+            if let Err(name) = template.render(&sample) {
+                let available = ["dist", "name", "name0", "name1"]
+                    .iter()
+                    .map(|alias| alias.to_string())
+                    .filter(|alias| Template::lookup(&sample, alias).is_some())
+                    .chain(sample.as_object().unwrap().keys().cloned()) // structs are objects
+                    .sorted()
+                    .collect::<Vec<_>>();
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "unknown placeholder {{{}}} in template; available: {{{}}}",
+                        name,
+                        available.join("}, {")
+                    ),
+                ));
+            }
+            for record in records.iter() {
+                let fields = serde_json::to_value(Line { settings, record })?;
+                writeln!(out, "{}", template.render(&fields).unwrap())?; // placeholders checked above
+            }
+        }
+        OutputFormat::Text => unreachable!("Text output is logged to the console instead"),
+        OutputFormat::Fdupes | OutputFormat::Rmlint => unreachable!("Groups are written by dedicated functions"),
+    }
+    out.flush()
+}
+
+/// This function writes duplicate groups in fdupes' format:
+/// one path per line, with a blank line after each group.
+///
+/// Paths are written as they were found, like fdupes does.
+pub fn write_fdupes(out: &mut impl Write, groups: &[Vec<&Path>]) -> std::io::Result<()> {
+    for group in groups.iter() {
+        for path in group.iter() {
+            writeln!(out, "{}", path.display())?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+/// This function writes duplicate groups in rmlint's JSON format:
+/// an array of a header object, one object per file, and a footer object
+/// with the totals.
+///
+/// The files of each group should be listed consecutively.
+pub fn write_rmlint(out: &mut impl Write, settings: &HashSettings, files: Vec<RmlintFile>) -> std::io::Result<()> {
+    #[derive(Serialize)]
+    struct Header {
+        description: &'static str,
+        cwd: String,
+        args: String,
+        version: &'static str,
+        progress: u32,
+        checksum_type: String,
+    }
+    #[derive(Serialize)]
+    struct Footer {
+        aborted: bool,
+        progress: u32,
+        duplicates: usize,
+        duplicate_sets: usize,
+        total_lint_size: u64,
+    }
+
+    let header = Header {
+        description: "rmlint json-dump of lint files",
+        cwd: std::env::current_dir()?.to_string_lossy().into_owned(),
+        args: std::env::args().join(" "),
+        version: env!("CARGO_PKG_VERSION"),
+        progress: 0,
+        checksum_type: format!(
+            "{}-{}x{}",
+            settings.algorithm, settings.hash_size.0, settings.hash_size.1
+        ),
+    };
+    let duplicates = files.iter().filter(|file| !file.is_original);
+    let footer = Footer {
+        aborted: false,
+        progress: 100,
+        duplicates: duplicates.clone().count(),
+        duplicate_sets: files.iter().filter(|file| file.is_original).count(),
+        total_lint_size: duplicates.map(|file| file.size).sum(),
+    };
+
+    // written element by element to keep each object's fields in rmlint's order
+    writeln!(out, "[")?;
+    serde_json::to_writer_pretty(&mut *out, &header)?;
+    let total = files.len();
+    for (n, file) in files.into_iter().enumerate() {
+        writeln!(out, ",")?;
+        let file = RmlintFile {
+            id: n + 1,
+            progress: ((n + 1) * 100 / total) as u32,
+            ..file
+        };
+        serde_json::to_writer_pretty(&mut *out, &file)?;
+    }
+    writeln!(out, ",")?;
+    serde_json::to_writer_pretty(&mut *out, &footer)?;
+    writeln!(out, "\n]")?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: HashSettings = HashSettings {
+        algorithm: "gradient",
+        hash_size: (8, 8),
+    };
+
+    fn records() -> Vec<HashRecord> {
+        vec![
+            HashRecord {
+                path: "/a.jpg".into(),
+                root: Some("/".into()),
+                size: Some(3),
+                width: Some(2),
+                height: Some(1),
+                hash: "AAAAAAAAAAA=".into(),
+            },
+            HashRecord {
+                path: "/b \"quoted\".jpg".into(),
+                hash: "//////////8=".into(),
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn written(format: OutputFormat) -> String {
+        let mut out = vec![];
+        write_records(&mut out, &format, &SETTINGS, "images", &records()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn json() {
+        let document: serde_json::Value = serde_json::from_str(&written(OutputFormat::Json)).unwrap();
+        assert_eq!(document["algorithm"], "gradient");
+        assert_eq!(document["hash_size"], serde_json::json!([8, 8]));
+        assert_eq!(document["images"][0]["path"], "/a.jpg");
+        assert_eq!(document["images"][0]["size"], 3);
+        assert_eq!(document["images"][1]["path"], "/b \"quoted\".jpg");
+        assert_eq!(document["images"][1]["size"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn ndjson() {
+        let output = written(OutputFormat::Ndjson);
+        let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        for (line, record) in lines.iter().zip(records()) {
+            // the settings are repeated on every line
+            assert_eq!(line["algorithm"], "gradient");
+            assert_eq!(line["path"], record.path);
+            assert_eq!(line["hash"], record.hash);
+        }
+    }
+
+    #[test]
+    fn csv_quotes_fields() {
+        let records = vec![HashRecord {
+            path: "/a, \"b\"\tc\nd.jpg".into(),
+            hash: "AAAAAAAAAAA=".into(),
+            ..Default::default()
+        }];
+        let mut out = vec![];
+        write_records(&mut out, &OutputFormat::Csv, &SETTINGS, "images", &records).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "path,root,size,width,height,hash\n\"/a, \"\"b\"\"\tc\nd.jpg\",,,,,AAAAAAAAAAA=\n"
+        );
+        assert_eq!(
+            written(OutputFormat::Csv),
+            "path,root,size,width,height,hash\n\
+            /a.jpg,/,3,2,1,AAAAAAAAAAA=\n\
+            \"/b \"\"quoted\"\".jpg\",,,,,//////////8=\n"
+        );
+    }
+
+    #[test]
+    fn tsv_header() {
+        assert_eq!(
+            written(OutputFormat::Tsv).lines().next(),
+            Some("path\troot\tsize\twidth\theight\thash")
+        );
+
+        // the header is written even if there are no records
+        let mut out = vec![];
+        write_records::<PairRecord>(&mut out, &OutputFormat::Tsv, &SETTINGS, "pairs", &[]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "path0\troot0\tsize0\twidth0\theight0\thash0\t\
+            path1\troot1\tsize1\twidth1\theight1\thash1\tdistance\n"
+        );
+    }
+
+    #[test]
+    fn template() {
+        let template = crate::cli_helper::parse_template(r"{name}\t{size}\t{algorithm}").unwrap();
+        assert_eq!(
+            written(OutputFormat::Template(template)),
+            "a.jpg\t3\tgradient\nb \"quoted\".jpg\t\tgradient\n"
+        );
+
+        let template = crate::cli_helper::parse_template("{path} {nope}").unwrap();
+        let mut out = vec![];
+        let err = write_records(
+            &mut out,
+            &OutputFormat::Template(template),
+            &SETTINGS,
+            "images",
+            &records(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unknown placeholder {nope}"), "{}", err);
+        assert!(out.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn full_path_refuses_non_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+        assert_eq!(full_path(Path::new("/a b/c.jpg")), Ok("/a b/c.jpg".to_string()));
+        assert!(full_path(Path::new(OsStr::from_bytes(b"/caf\xe9.jpg"))).is_err());
+    }
+
+    #[test]
+    fn fdupes_golden() {
+        let groups = vec![
+            vec![Path::new("/photos/a.jpg"), Path::new("/backup/a copy.jpg")],
+            vec![Path::new("b.png"), Path::new("old/b.png"), Path::new("old/b (1).png")],
+        ];
+        let mut out = vec![];
+        write_fdupes(&mut out, &groups).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "/photos/a.jpg\n/backup/a copy.jpg\n\nb.png\nold/b.png\nold/b (1).png\n\n"
+        );
+    }
+
+    #[test]
+    fn rmlint_golden() {
+        let file = |path: &str, checksum: &str, size, is_original| RmlintFile {
+            id: 0,
+            kind: "duplicate_file",
+            progress: 0,
+            checksum: checksum.to_string(),
+            path: path.to_string(),
+            size,
+            depth: Path::new(path).components().count() - 1,
+            inode: Some(size * 10),
+            disk_id: Some(2049),
+            is_original,
+            mtime: 1_600_000_000.5,
+        };
+        let files = vec![
+            file("/photos/a.jpg", "00ff", 300, true),
+            file("/backup/old/a.jpg", "00ff", 300, false),
+            file("/photos/b.jpg", "0f0f", 100, true),
+            file("/photos/b copy.jpg", "0f0f", 100, false),
+        ];
+        let mut out = vec![];
+        write_rmlint(&mut out, &SETTINGS, files).unwrap();
+
+        // the working directory and arguments depend on how the tests are run
+        let cwd = serde_json::to_string(&std::env::current_dir().unwrap().to_string_lossy()).unwrap();
+        let args = serde_json::to_string(&std::env::args().join(" ")).unwrap();
+        let file_json = |id, progress, checksum, path, size, depth, is_original| {
+            format!(
+                "{{\n  \"id\": {},\n  \"type\": \"duplicate_file\",\n  \"progress\": {},\n  \
+                \"checksum\": \"{}\",\n  \"path\": \"{}\",\n  \"size\": {},\n  \"depth\": {},\n  \
+                \"inode\": {},\n  \"disk_id\": 2049,\n  \"is_original\": {},\n  \"mtime\": 1600000000.5\n}}",
+                id,
+                progress,
+                checksum,
+                path,
+                size,
+                depth,
+                size * 10,
+                is_original
+            )
+        };
+        let expected = [
+            format!(
+                "[\n{{\n  \"description\": \"rmlint json-dump of lint files\",\n  \"cwd\": {},\n  \
+                \"args\": {},\n  \"version\": \"{}\",\n  \"progress\": 0,\n  \
+                \"checksum_type\": \"gradient-8x8\"\n}}",
+                cwd,
+                args,
+                env!("CARGO_PKG_VERSION")
+            ),
+            file_json(1, 25, "00ff", "/photos/a.jpg", 300, 2, true),
+            file_json(2, 50, "00ff", "/backup/old/a.jpg", 300, 3, false),
+            file_json(3, 75, "0f0f", "/photos/b.jpg", 100, 2, true),
+            file_json(4, 100, "0f0f", "/photos/b copy.jpg", 100, 2, false),
+            "{\n  \"aborted\": false,\n  \"progress\": 100,\n  \"duplicates\": 2,\n  \
+            \"duplicate_sets\": 2,\n  \"total_lint_size\": 400\n}\n]\n"
+                .to_string(),
+        ]
+        .join(",\n");
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
@@ -0,0 +1,229 @@
+//! This module implements plan files, which list the action to take
+//! on each member of each duplicate group, so that they can be reviewed
+//! and edited before anything is modified.
+//!
+//! A plan is a TOML file written by `scan-duplicates --plan`,
+//! and executed by the `apply` subcommand. The size and modification time
+//! of each file are recorded, so that files changed since the scan
+//! are detected before the plan is applied.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The action to take on a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanAction {
+    /// Leave the file as is.
+    Keep,
+    /// Move the file to the destination directory.
+    Move,
+    /// Move the file to the trash (or delete it permanently).
+    Delete,
+    /// Replace the file with a link to its group's keeper.
+    Link,
+}
+
+/// A member of a duplicate group, as recorded in a plan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanFile {
+    pub path: PathBuf,
+    pub action: PlanAction,
+    /// The hamming distance from the file to its group's keeper.
+    pub distance: u32,
+    /// The size of the file in bytes, at the time of the scan.
+    pub size: u64,
+    /// The modification time of the file in RFC 3339 format, at the time of the scan.
+    pub mtime: String,
+}
+impl PlanFile {
+    /// Record the current size and modification time of a file.
+    ///
+    /// The path is made absolute, so that the plan
+    /// does not depend on the working directory.
+    pub fn of(path: &Path, action: PlanAction, distance: u32) -> std::io::Result<Self> {
+        let meta = fs::metadata(path)?;
+        Ok(Self {
+            path: std::path::absolute(path)?,
+            action,
+            distance,
+            size: meta.len(),
+            mtime: format_mtime(meta.modified()?),
+        })
+    }
+
+    /// Check that the file still has the size and modification time
+    /// recorded in the plan.
+    pub fn check_unchanged(&self) -> Result<(), String> {
+        let meta = fs::metadata(&self.path).map_err(|e| e.to_string())?;
+        if meta.len() != self.size {
+            return Err(format!("size changed from {} to {} bytes", self.size, meta.len()));
+        }
+        let mtime = format_mtime(meta.modified().map_err(|e| e.to_string())?);
+        if mtime != self.mtime {
+            return Err(format!("modified at {} since the scan", mtime));
+        }
+        Ok(())
+    }
+}
+
+/// A duplicate group, as recorded in a plan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanGroup {
+    /// The ID of the group, as shown to the user (starting from 1).
+    pub id: usize,
+    /// The file that all other members are linked to, if their action is `link`.
+    /// Its own action must be `keep`.
+    pub keeper: PathBuf,
+    pub files: Vec<PlanFile>,
+}
+impl PlanGroup {
+    /// Check that the keeper is listed in the group's files,
+    /// and that it is kept, since the other files may be linked to it.
+    pub fn check_keeper(&self) -> Result<(), String> {
+        match self.files.iter().find(|file| file.path == self.keeper) {
+            Some(file) if file.action != PlanAction::Keep => {
+                Err(format!("the keeper {:?} must have the keep action", self.keeper))
+            }
+            Some(_) => Ok(()),
+            None => Err(format!(
+                "the keeper {:?} is not listed in the group's files",
+                self.keeper
+            )),
+        }
+    }
+}
+
+/// A plan listing the action to take on each member of each duplicate group.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    pub groups: Vec<PlanGroup>,
+}
+
+const PLAN_HEADER: &str = "\
+# Duplicate image plan, written by img_dedup.
+# Review and edit the action of each file, then run `img_dedup apply <this file>`.
+# Available actions:
+#   keep    leave the file as is
+#   move    move the file to the directory given to `apply --destination`
+#   delete  move the file to the trash (or delete it with `apply --permanent`)
+#   link    replace the file with a link to the group's keeper (which must be kept)
+# Files whose size or mtime changed since the scan are refused.
+
+";
+
+/// Formats a modification time in RFC 3339 format, to nanosecond precision.
+fn format_mtime(mtime: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(mtime).to_rfc3339_opts(chrono::SecondsFormat::Nanos, true)
+}
+
+/// This function writes a plan to a file, preceded by instructions for editing it.
+pub fn write_plan(path: &Path, plan: &Plan) -> Result<(), String> {
+    let content = toml::to_string_pretty(plan).map_err(|e| e.to_string())?;
+    fs::write(path, format!("{}{}", PLAN_HEADER, content)).map_err(|e| e.to_string())
+}
+
+/// This function reads a plan from a file.
+pub fn read_plan(path: &Path) -> Result<Plan, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    toml::from_str(&content).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+    use std::{fs::File, time::Duration};
+
+    /// A plan of a single group of files with the specified names and actions,
+    /// the first of which is the keeper.
+    fn plan_of(dir: &Path, files: &[(&str, PlanAction)]) -> Plan {
+        let files: Vec<_> = files
+            .iter()
+            .map(|&(name, action)| {
+                let path = dir.join(name);
+                fs::write(&path, name).unwrap();
+                PlanFile::of(&path, action, 1).unwrap()
+            })
+            .collect();
+        Plan {
+            groups: vec![PlanGroup {
+                id: 1,
+                keeper: files[0].path.clone(),
+                files,
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = test_dir("plan-round-trip");
+        let plan = plan_of(
+            &dir,
+            &[
+                ("keep.jpg", PlanAction::Keep),
+                ("a \"b\".jpg", PlanAction::Move),
+                ("c.jpg", PlanAction::Delete),
+                ("d.jpg", PlanAction::Link),
+            ],
+        );
+        let plan_path = dir.join("plan.toml");
+        write_plan(&plan_path, &plan).unwrap();
+
+        let content = fs::read_to_string(&plan_path).unwrap();
+        assert!(content.starts_with(PLAN_HEADER), "{}", content);
+        assert!(content.contains("action = 'link'"), "{}", content);
+        assert_eq!(read_plan(&plan_path).unwrap(), plan);
+
+        fs::write(&plan_path, content.replace("'link'", "'copy'")).unwrap();
+        assert!(read_plan(&plan_path).is_err());
+    }
+
+    #[test]
+    fn changed_files_are_detected() {
+        let dir = test_dir("plan-changed");
+        let plan = plan_of(&dir, &[("a.jpg", PlanAction::Keep), ("b.jpg", PlanAction::Delete)]);
+        let (a, b) = (&plan.groups[0].files[0], &plan.groups[0].files[1]);
+        assert_eq!(a.check_unchanged(), Ok(()));
+
+        fs::write(&a.path, "changed").unwrap();
+        let err = a.check_unchanged().unwrap_err();
+        assert!(err.starts_with("size changed from 5 to 7 bytes"), "{}", err);
+
+        // same size, but touched
+        let mtime = SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(&b.path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let err = b.check_unchanged().unwrap_err();
+        assert!(err.starts_with("modified at "), "{}", err);
+
+        fs::remove_file(&b.path).unwrap();
+        assert!(b.check_unchanged().is_err());
+    }
+
+    #[test]
+    fn keeper_must_be_kept() {
+        let dir = test_dir("plan-keeper");
+        let plan = plan_of(&dir, &[("a.jpg", PlanAction::Keep), ("b.jpg", PlanAction::Link)]);
+        assert_eq!(plan.groups[0].check_keeper(), Ok(()));
+
+        let mut group = plan.groups[0].clone();
+        group.files[0].action = PlanAction::Link;
+        let err = group.check_keeper().unwrap_err();
+        assert!(err.ends_with("must have the keep action"), "{}", err);
+
+        let mut group = plan.groups[0].clone();
+        group.keeper = dir.join("elsewhere.jpg");
+        let err = group.check_keeper().unwrap_err();
+        assert!(err.ends_with("is not listed in the group's files"), "{}", err);
+    }
+}
@@ -0,0 +1,291 @@
+//! This module renders small thumbnails of images inline in the terminal,
+//! so that duplicates can be told apart without a file manager (e.g. over SSH).
+//!
+//! The thumbnails of a pair or group are placed side by side into a single strip,
+//! which is then drawn with the kitty graphics protocol, as sixels,
+//! or as colored half blocks in terminals supporting neither.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    env,
+    fmt::Write as _,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use image::{imageops, DynamicImage, ImageFormat, ImageOutputFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use itertools::Itertools;
+
+/// The way thumbnails are drawn in the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewProtocol {
+    /// The kitty graphics protocol (kitty, WezTerm, Ghostty, Konsole).
+    Kitty,
+    /// Sixel graphics (xterm, foot, mlterm, Windows Terminal, ...).
+    Sixel,
+    /// Half-block characters in 24-bit color, which most terminals support.
+    Text,
+}
+impl PreviewProtocol {
+    /// Guess the best protocol supported by the terminal, from its environment variables.
+    ///
+    /// Only `TERM` is usually forwarded over SSH, so other hints are merely a bonus.
+    pub fn detect() -> Self {
+        let var = |name| env::var(name).unwrap_or_default().to_lowercase();
+        let (term, term_program) = (var("TERM"), var("TERM_PROGRAM"));
+        if term.contains("kitty")
+            || term.contains("ghostty")
+            || env::var_os("KITTY_WINDOW_ID").is_some()
+            || ["wezterm", "ghostty"].contains(&term_program.as_str())
+        {
+            Self::Kitty
+        } else if term.contains("sixel")
+            || term.starts_with("foot")
+            || term.starts_with("mlterm")
+            || term.starts_with("yaft")
+            || env::var_os("WT_SESSION").is_some()
+        {
+            Self::Sixel
+        } else {
+            Self::Text
+        }
+    }
+}
+
+/// Shrink an image to a thumbnail fitting within `size`×`size` pixels,
+/// preserving its aspect ratio.
+pub fn make_thumbnail(img: &DynamicImage, size: u32) -> RgbaImage {
+    img.thumbnail(size, size).to_rgba8()
+}
+
+/// Encode a thumbnail as JPEG, with transparent parts on white.
+///
+/// This keeps thumbnails compact, so that many of them can be held in memory.
+pub fn encode_jpeg(thumbnail: &RgbaImage) -> image::ImageResult<Vec<u8>> {
+    let flattened = RgbImage::from_fn(thumbnail.width(), thumbnail.height(), |x, y| {
+        let pixel = thumbnail.get_pixel(x, y);
+        let alpha = u32::from(pixel[3]);
+        Rgb([0, 1, 2].map(|c| ((u32::from(pixel[c]) * alpha + 255 * (255 - alpha)) / 255) as u8))
+    });
+    let mut jpeg = vec![];
+    DynamicImage::ImageRgb8(flattened).write_to(&mut jpeg, ImageOutputFormat::Jpeg(85))?;
+    Ok(jpeg)
+}
+
+/// Draws thumbnails of images, made while hashing where possible.
+pub struct Previewer {
+    protocol: PreviewProtocol,
+    /// The maximum width and height of a thumbnail, in pixels.
+    size: u32,
+    /// Thumbnails encoded by [`encode_jpeg`].
+    thumbnails: HashMap<PathBuf, Vec<u8>>,
+}
+impl Previewer {
+    /// The space between thumbnails, in pixels (or columns for text).
+    const GAP: u32 = 8;
+    /// The number of pixels per thumbnail pixel in text, which is drawn at one column per pixel.
+    const TEXT_SCALE: u32 = 8;
+
+    pub fn new(protocol: PreviewProtocol, size: u32, thumbnails: HashMap<PathBuf, Vec<u8>>) -> Self {
+        Self {
+            protocol,
+            size,
+            thumbnails,
+        }
+    }
+
+    /// Drop the thumbnails of all images but the given ones,
+    /// e.g. once it is known which images are duplicates.
+    pub fn retain(&mut self, paths: &HashSet<&Path>) {
+        self.thumbnails.retain(|path, _| paths.contains(path.as_path()));
+    }
+
+    /// The thumbnail of an image, decoding it if it was not decoded while hashing
+    /// (e.g. because its hash was cached). Unreadable images are shown as blank.
+    fn thumbnail(&self, path: &Path) -> RgbaImage {
+        let thumbnail = match self.thumbnails.get(path) {
+            Some(jpeg) => image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg).map(|img| img.to_rgba8()),
+            None => image::open(path).map(|img| make_thumbnail(&img, self.size)),
+        };
+        thumbnail.unwrap_or_else(|_| RgbaImage::new(self.size, self.size))
+    }
+
+    /// The width of the terminal, in pixels for graphics protocols and in columns for text.
+    fn available_width(&self) -> u32 {
+        let columns = crossterm::terminal::size().map_or(80, |(columns, _)| u32::from(columns));
+        match self.protocol {
+            PreviewProtocol::Text => columns,
+            _ => match crossterm::terminal::window_size() {
+                Ok(window) if window.width > 0 => u32::from(window.width),
+                _ => columns * 8, // assume a typical cell width
+            },
+        }
+    }
+
+    /// This function draws the thumbnails of the images side by side, in the given order,
+    /// wrapping onto more rows if they do not fit in the terminal.
+    pub fn show(&self, out: &mut impl Write, paths: &[&Path]) -> io::Result<()> {
+        let mut thumbnails: Vec<_> = paths.iter().map(|path| self.thumbnail(path)).collect();
+        if self.protocol == PreviewProtocol::Text {
+            let size = (self.size / Self::TEXT_SCALE).max(1);
+            thumbnails = thumbnails
+                .iter()
+                .map(|thumbnail| imageops::thumbnail(thumbnail, size, size))
+                .collect();
+        }
+
+        let slot_width = thumbnails.iter().map(|t| t.width()).max().unwrap_or(1) + Self::GAP;
+        let per_row = (self.available_width() / slot_width).max(1) as usize;
+        for row in thumbnails.chunks(per_row) {
+            let strip = compose_strip(row, Self::GAP);
+            let encoded = match self.protocol {
+                PreviewProtocol::Kitty => encode_kitty(&strip),
+                PreviewProtocol::Sixel => encode_sixel(&strip),
+                PreviewProtocol::Text => encode_half_blocks(&strip),
+            };
+            writeln!(out, "{}", encoded)?;
+        }
+        out.flush()
+    }
+}
+
+/// Place images side by side, aligned to the top, with transparent gaps.
+fn compose_strip(images: &[RgbaImage], gap: u32) -> RgbaImage {
+    let width = images
+        .iter()
+        .map(|img| img.width() + gap)
+        .sum::<u32>()
+        .saturating_sub(gap);
+    let height = images.iter().map(|img| img.height()).max().unwrap_or(0);
+    let mut strip = RgbaImage::new(width.max(1), height.max(1));
+    let mut x = 0;
+    for img in images.iter() {
+        imageops::overlay(&mut strip, img, x, 0);
+        x += img.width() + gap;
+    }
+    strip
+}
+
+fn is_opaque(pixel: &Rgba<u8>) -> bool {
+    pixel[3] >= 128
+}
+
+/// Encode an image with the kitty graphics protocol, as raw RGBA data.
+fn encode_kitty(img: &RgbaImage) -> String {
+    const CHUNK_SIZE: usize = 4096;
+
+    let data = base64::encode(img.as_raw());
+    let chunks: Vec<_> = data.as_bytes().chunks(CHUNK_SIZE).collect();
+    let mut encoded = String::new();
+    for (n, chunk) in chunks.iter().enumerate() {
+        let more = (n + 1 < chunks.len()) as u8;
+        let chunk = std::str::from_utf8(chunk).unwrap(); // base64 is ASCII
+        match n {
+            // transmit and display, suppressing the terminal's responses
+            0 => write!(
+                encoded,
+                "\x1b_Ga=T,f=32,q=2,s={},v={},m={};{}\x1b\\",
+                img.width(),
+                img.height(),
+                more,
+                chunk
+            ),
+            _ => write!(encoded, "\x1b_Gm={};{}\x1b\\", more, chunk),
+        }
+        .unwrap(); // writing to String is infallible
+    }
+    encoded
+}
+
+/// Encode an image as sixels, with colors reduced to a 6×6×6 color cube.
+/// Transparent pixels are left as the terminal's background.
+fn encode_sixel(img: &RgbaImage) -> String {
+    // quantize a channel to one of 6 levels
+    let level = |channel: u8| (u32::from(channel) * 5 + 127) / 255;
+    let color_index = |pixel: &Rgba<u8>| level(pixel[0]) * 36 + level(pixel[1]) * 6 + level(pixel[2]);
+
+    // enter sixel mode with transparent background, and set square pixels and the size
+    let mut encoded = format!("\x1bP0;1;0q\"1;1;{};{}", img.width(), img.height());
+    for index in 0..216 {
+        let percent = |level: u32| level * 100 / 5;
+        write!(
+            encoded,
+            "#{};2;{};{};{}",
+            index,
+            percent(index / 36),
+            percent(index / 6 % 6),
+            percent(index % 6)
+        )
+        .unwrap(); // writing to String is infallible
+    }
+
+    // each sixel is a column of 6 pixels
+    for band_top in (0..img.height()).step_by(6) {
+        let band_rows = band_top..(band_top + 6).min(img.height());
+        let colors: BTreeSet<_> = band_rows
+            .clone()
+            .flat_map(|y| (0..img.width()).map(move |x| img.get_pixel(x, y)))
+            .filter(|pixel| is_opaque(pixel))
+            .map(color_index)
+            .collect();
+
+        for (n, &color) in colors.iter().enumerate() {
+            // return to the start of the band for every color but the first
+            if n > 0 {
+                encoded.push('$');
+            }
+            write!(encoded, "#{}", color).unwrap(); // writing to String is infallible
+
+            let sixels = (0..img.width()).map(|x| {
+                let bits = band_rows
+                    .clone()
+                    .filter(|&y| {
+                        let pixel = img.get_pixel(x, y);
+                        is_opaque(pixel) && color_index(pixel) == color
+                    })
+                    .fold(0, |bits, y| bits | 1 << (y - band_top));
+                (63 + bits) as u8 as char
+            });
+            // run-length encode repeated sixels
+            for (count, sixel) in sixels.dedup_with_count() {
+                match count {
+                    1..=3 => (0..count).for_each(|_| encoded.push(sixel)),
+                    _ => write!(encoded, "!{}{}", count, sixel).unwrap(), // writing to String is infallible
+                }
+            }
+        }
+        encoded.push('-');
+    }
+    encoded.push_str("\x1b\\");
+    encoded
+}
+
+/// Encode an image as half-block characters in 24-bit color, two pixels per character.
+fn encode_half_blocks(img: &RgbaImage) -> String {
+    let mut encoded = String::new();
+    for y in (0..img.height()).step_by(2) {
+        if y > 0 {
+            encoded.push('\n');
+        }
+        for x in 0..img.width() {
+            let top = Some(img.get_pixel(x, y)).filter(|pixel| is_opaque(pixel));
+            let bottom = Some(y + 1)
+                .filter(|&y| y < img.height())
+                .map(|y| img.get_pixel(x, y))
+                .filter(|pixel| is_opaque(pixel));
+            match (top, bottom) {
+                (Some(top), Some(bottom)) => write!(
+                    encoded,
+                    "\x1b[38;2;{};{};{};48;2;{};{};{}m▀",
+                    top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]
+                ),
+                (Some(top), None) => write!(encoded, "\x1b[0;38;2;{};{};{}m▀", top[0], top[1], top[2]),
+                (None, Some(bottom)) => write!(encoded, "\x1b[0;38;2;{};{};{}m▄", bottom[0], bottom[1], bottom[2]),
+                (None, None) => write!(encoded, "\x1b[0m "),
+            }
+            .unwrap(); // writing to String is infallible
+        }
+        encoded.push_str("\x1b[0m");
+    }
+    encoded
+}
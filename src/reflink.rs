@@ -0,0 +1,111 @@
+//! This module implements sharing of storage between identical files,
+//! using the Linux `FIDEDUPERANGE` ioctl.
+//!
+//! The kernel compares the file ranges itself, and only shares their
+//! extents if they are identical, so this is safe against concurrent
+//! modification. It is supported by copy-on-write filesystems
+//! such as Btrfs and XFS.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind},
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+
+/// `_IOWR(0x94, 54, struct file_dedupe_range)`, see `linux/fs.h`.
+const FIDEDUPERANGE: libc::c_ulong = 0xC0189436;
+/// The ranges are identical, and were deduplicated.
+const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+/// The ranges differ, and nothing was done.
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+/// Some filesystems limit how much is deduplicated per call,
+/// so larger files are submitted in chunks.
+const MAX_CHUNK_LEN: u64 = 16 * 1024 * 1024;
+
+/// `struct file_dedupe_range_info`, see `linux/fs.h`.
+#[repr(C)]
+struct FileDedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+/// `struct file_dedupe_range` followed by a single destination,
+/// see `linux/fs.h`.
+#[repr(C)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    info: [FileDedupeRangeInfo; 1],
+}
+
+/// This function makes `dest` share the storage of `src`.
+/// Both files are expected to have identical contents.
+///
+/// Returns the number of bytes deduplicated.
+/// Fails with `Unsupported` if the filesystem does not support extent sharing,
+/// and with `CrossesDevices` if the files are on different filesystems.
+pub fn dedupe_file(src: &Path, dest: &Path) -> std::io::Result<u64> {
+    let src_file = File::open(src)?;
+    let dest_file = OpenOptions::new().write(true).open(dest)?;
+    let len = src_file.metadata()?.len();
+    if dest_file.metadata()?.len() != len {
+        return Err(Error::new(ErrorKind::InvalidData, "file sizes differ"));
+    }
+
+    let mut offset = 0;
+    while offset < len {
+        let mut range = FileDedupeRange {
+            src_offset: offset,
+            src_length: (len - offset).min(MAX_CHUNK_LEN),
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            info: [FileDedupeRangeInfo {
+                dest_fd: dest_file.as_raw_fd() as i64,
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            }],
+        };
+        // SAFETY: `range` matches the kernel's layout, and outlives the call
+        let ret = unsafe { libc::ioctl(src_file.as_raw_fd(), FIDEDUPERANGE as _, &mut range) };
+        if ret != 0 {
+            return Err(to_io_error(Error::last_os_error()));
+        }
+
+        let info = &range.info[0];
+        match info.status {
+            FILE_DEDUPE_RANGE_SAME => {}
+            FILE_DEDUPE_RANGE_DIFFERS => {
+                return Err(Error::new(ErrorKind::InvalidData, "file contents differ"));
+            }
+            errno => return Err(to_io_error(Error::from_raw_os_error(-errno))),
+        }
+        if info.bytes_deduped == 0 {
+            return Err(Error::other(format!("no progress made at offset {}", offset)));
+        }
+        offset += info.bytes_deduped;
+    }
+
+    Ok(len)
+}
+
+/// Normalises the errors the kernel uses to signal
+/// that a filesystem does not support deduplication.
+fn to_io_error(e: Error) -> Error {
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::ENOSYS) => {
+            Error::new(ErrorKind::Unsupported, "filesystem does not support extent sharing")
+        }
+        Some(libc::EXDEV) => Error::new(ErrorKind::CrossesDevices, "files are on different filesystems"),
+        _ => e,
+    }
+}
@@ -0,0 +1,242 @@
+//! This module writes the HTML report of `scan-duplicates --report`,
+//! so that duplicate groups can be reviewed in a browser by anyone,
+//! without running the CLI.
+//!
+//! The report is a single self-contained file, with thumbnails embedded as base64.
+//! It is written one group at a time, so that the thumbnails of all groups
+//! never have to be held in memory at once.
+
+use std::io::{self, Write};
+
+use chrono::Local;
+
+use crate::{
+    cluster::DupGroup,
+    compute::SizedThumbnail,
+    output::{full_path, HashSettings},
+};
+
+/// The maximum width and height of thumbnails in the report, in pixels.
+pub const REPORT_THUMBNAIL_SIZE: u32 = 256;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; background: #f6f6f6; }
+section { background: #fff; border: 1px solid #ddd; border-radius: 6px; padding: 0 1em 1em; margin-bottom: 2em; }
+.images { display: flex; flex-wrap: wrap; gap: 1em; }
+figure { margin: 0; width: 256px; }
+figure img { display: block; margin: auto; max-width: 256px; max-height: 256px; }
+figcaption { font-size: 0.85em; margin-top: 0.5em; overflow-wrap: anywhere; }
+.missing { display: flex; height: 128px; align-items: center; justify-content: center; background: #eee; color: #888; }
+.note { color: #666; }
+table { border-collapse: collapse; margin-top: 1em; font-size: 0.85em; }
+caption { text-align: left; color: #666; margin-bottom: 0.25em; }
+th, td { border: 1px solid #ddd; padding: 0.25em 0.6em; text-align: right; }
+";
+
+/// Escape text for use in HTML content and attribute values.
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Format a file size with binary units, e.g. `1.5 MiB`.
+fn fmt_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Encode a thumbnail as a JPEG data URI, with transparent parts on white.
+fn data_uri(jpeg: &[u8]) -> String {
+    format!("data:image/jpeg;base64,{}", base64::encode(jpeg))
+}
+
+/// This function writes the beginning of the HTML report of a list of duplicate groups,
+/// which is followed by [`write_report_group`] for each group, and [`write_report_end`].
+pub fn write_report_start(out: &mut impl Write, groups: &[DupGroup], settings: &HashSettings) -> io::Result<()> {
+    let image_count: usize = groups.iter().map(|group| group.members.len()).sum();
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html lang=\"en\">")?;
+    writeln!(out, "<head>")?;
+    writeln!(out, "<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>Duplicate images</title>")?;
+    writeln!(out, "<style>{}</style>", STYLE)?;
+    writeln!(out, "</head>")?;
+    writeln!(out, "<body>")?;
+    writeln!(out, "<h1>Duplicate images</h1>")?;
+    writeln!(
+        out,
+        "<p>{} group(s) containing {} image(s), found with the {} hash of size {}&times;{}. \
+        Generated by img_dedup on {}.</p>",
+        groups.len(),
+        image_count,
+        escape_html(settings.algorithm),
+        settings.hash_size.0,
+        settings.hash_size.1,
+        Local::now().format("%Y-%m-%d %H:%M"),
+    )?;
+    writeln!(
+        out,
+        "<p class=\"note\">Distances compare the images' perceptual hashes: \
+        0 means they look the same, and larger numbers mean they look less alike.</p>"
+    )
+}
+
+/// This function writes a duplicate group of the HTML report, with its ID
+/// (as shown to the user, starting from 1).
+///
+/// Each member's thumbnail, path, dimensions and file size are shown,
+/// followed by a table of the distances between all members.
+/// `thumbnails` holds the thumbnail of each member, in the same order,
+/// or `None` for members that could not be decoded.
+pub fn write_report_group(
+    out: &mut impl Write,
+    id: usize,
+    group: &DupGroup,
+    thumbnails: &[Option<SizedThumbnail>],
+) -> io::Result<()> {
+    writeln!(out, "<section id=\"group-{}\">", id)?;
+    writeln!(out, "<h2>Group {}</h2>", id)?;
+    writeln!(
+        out,
+        "<p>{} images; the largest distance between any two is {}.</p>",
+        group.members.len(),
+        group.diameter()
+    )?;
+
+    writeln!(out, "<div class=\"images\">")?;
+    for (n, &(path, _)) in group.members.iter().enumerate() {
+        // only shown to the user, so replacing invalid UTF-8 is harmless
+        let full_path = escape_html(&full_path(path).unwrap_or_else(|_| path.to_string_lossy().into_owned()));
+        let file_size = std::fs::metadata(path).map_or("unknown size".into(), |meta| fmt_size(meta.len()));
+        let (picture, dimensions) = match thumbnails.get(n).and_then(Option::as_ref) {
+            Some((thumbnail, (width, height))) => (
+                format!("<img src=\"{}\" alt=\"Image #{}\">", data_uri(thumbnail), n + 1),
+                format!("{} &times; {}", width, height),
+            ),
+            None => (
+                "<div class=\"missing\">Cannot be displayed</div>".to_string(),
+                "unknown dimensions".to_string(),
+            ),
+        };
+        writeln!(
+            out,
+            "<figure>{}<figcaption><b>#{}</b>{}<br>{}<br>{} &middot; {}</figcaption></figure>",
+            picture,
+            n + 1,
+            if n == 0 { " (representative)" } else { "" },
+            full_path,
+            dimensions,
+            file_size,
+        )?;
+    }
+    writeln!(out, "</div>")?;
+
+    writeln!(out, "<table>")?;
+    writeln!(out, "<caption>Distances between images</caption>")?;
+    write!(out, "<tr><th></th>")?;
+    for n in 0..group.members.len() {
+        write!(out, "<th>#{}</th>", n + 1)?;
+    }
+    writeln!(out, "</tr>")?;
+    for i in 0..group.members.len() {
+        write!(out, "<tr><th>#{}</th>", i + 1)?;
+        for j in 0..group.members.len() {
+            write!(out, "<td>{}</td>", group.dist(i, j))?;
+        }
+        writeln!(out, "</tr>")?;
+    }
+    writeln!(out, "</table>")?;
+    writeln!(out, "</section>")?;
+    Ok(())
+}
+
+/// This function writes the end of the HTML report.
+pub fn write_report_end(out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "</body>")?;
+    writeln!(out, "</html>")?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+    use img_hash::ImageHash;
+    use std::fs;
+
+    #[test]
+    fn escape() {
+        assert_eq!(
+            escape_html(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(fmt_size(0), "0 bytes");
+        assert_eq!(fmt_size(1023), "1023 bytes");
+        assert_eq!(fmt_size(1024), "1.0 KiB");
+        assert_eq!(fmt_size(1536 * 1024), "1.5 MiB");
+        assert_eq!(fmt_size(3 << 40), "3.0 TiB");
+        assert_eq!(fmt_size(2048 << 40), "2048.0 TiB");
+    }
+
+    #[test]
+    fn report_escapes_paths() {
+        let dir = test_dir("report");
+        let (a, b) = (dir.join("<b>&\"1\".jpg"), dir.join("b.jpg"));
+        fs::write(&a, [0; 2048]).unwrap();
+        fs::write(&b, "b").unwrap();
+        let hashes = [
+            ImageHash::from_bytes(&[0; 8]).unwrap(),
+            ImageHash::from_bytes(&[1; 8]).unwrap(),
+        ];
+        let group = DupGroup {
+            members: vec![(a.as_path(), 0), (b.as_path(), 8)],
+            hashes: hashes.iter().collect(),
+        };
+        let settings = HashSettings {
+            algorithm: "gradient",
+            hash_size: (8, 8),
+        };
+
+        let mut out = vec![];
+        write_report_start(&mut out, std::slice::from_ref(&group), &settings).unwrap();
+        write_report_group(&mut out, 1, &group, &[Some((vec![0xff, 0xd8], (640, 480))), None]).unwrap();
+        write_report_end(&mut out).unwrap();
+        let html = String::from_utf8(out).unwrap();
+
+        assert!(html.starts_with("<!DOCTYPE html>\n"), "{}", html);
+        assert!(html.ends_with("</body>\n</html>\n"), "{}", html);
+        assert!(html.contains("1 group(s) containing 2 image(s)"), "{}", html);
+        assert!(html.contains("/&lt;b&gt;&amp;&quot;1&quot;.jpg<br>"), "{}", html);
+        assert!(!html.contains("<b>&"), "{}", html);
+        assert!(
+            html.contains("<img src=\"data:image/jpeg;base64,/9g=\" alt=\"Image #1\">"),
+            "{}",
+            html
+        );
+        assert!(html.contains("640 &times; 480 &middot; 2.0 KiB"), "{}", html);
+        assert!(html.contains("Cannot be displayed"), "{}", html);
+        assert!(html.contains("unknown dimensions &middot; 1 bytes"), "{}", html);
+        assert!(html.contains("<tr><th>#2</th><td>8</td><td>0</td></tr>"), "{}", html);
+    }
+}
@@ -0,0 +1,340 @@
+//! This module implements the interactive terminal UI of the `review` subcommand,
+//! in which the user walks through the duplicate groups and decides
+//! what to do with each of their members.
+//!
+//! The UI is drawn on stderr, so that it never mixes with results on stdout.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Local};
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+
+use crate::{cluster::DupGroup, io::find_root};
+
+/// The decision for a member of a duplicate group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mark {
+    Keep,
+    Move,
+    Delete,
+    /// The member was grouped by mistake, and is left alone.
+    NotDuplicate,
+}
+impl Mark {
+    fn label(self) -> &'static str {
+        match self {
+            Mark::Keep => "keep",
+            Mark::Move => "move",
+            Mark::Delete => "delete",
+            Mark::NotDuplicate => "not dup",
+        }
+    }
+
+    fn is_removal(self) -> bool {
+        matches!(self, Mark::Move | Mark::Delete)
+    }
+}
+
+/// The information about a group member that is shown side by side with the others.
+/// Any information that cannot be determined is `None`.
+struct MemberInfo {
+    dimensions: Option<(u32, u32)>,
+    size: Option<u64>,
+    mtime: Option<SystemTime>,
+    /// The original date and time from the EXIF metadata, as written by the camera.
+    exif_date: Option<String>,
+}
+impl MemberInfo {
+    /// Gather information about a file. Only the image header and metadata are read.
+    fn of(path: &Path) -> Self {
+        let metadata = fs::metadata(path).ok();
+        Self {
+            dimensions: image::image_dimensions(path).ok(),
+            size: metadata.as_ref().map(|meta| meta.len()),
+            mtime: metadata.and_then(|meta| meta.modified().ok()),
+            exif_date: File::open(path).ok().and_then(|file| {
+                let exif = exif::Reader::new()
+                    .read_from_container(&mut BufReader::new(file))
+                    .ok()?;
+                let field = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)?;
+                Some(field.display_value().to_string())
+            }),
+        }
+    }
+}
+
+/// Switches the terminal to an alternate screen in raw mode,
+/// and restores it when dropped, even if the review fails.
+struct TerminalGuard;
+impl TerminalGuard {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stderr(), EnterAlternateScreen, cursor::Hide)?;
+        Ok(Self)
+    }
+}
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(io::stderr(), LeaveAlternateScreen, cursor::Show);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+const KEY_HELP: &str = "up/down: image  left/right: group  k: keep  m: move  d: delete  x: not a duplicate  \
+                        a: apply  q: quit";
+
+/// The state of an ongoing review.
+struct Review<'g, 'a> {
+    groups: &'g [DupGroup<'a>],
+    roots: &'g [PathBuf],
+    marks: Vec<Vec<Mark>>,
+    /// Loaded when a group is first shown.
+    infos: Vec<Option<Vec<MemberInfo>>>,
+    group: usize,
+    member: usize,
+    /// The first member row shown, if the group does not fit on screen.
+    scroll: usize,
+    can_move: bool,
+    confirming: bool,
+    status: String,
+}
+impl<'g, 'a> Review<'g, 'a> {
+    /// The member the others are compared to: the first one marked to keep,
+    /// or the representative if there is none.
+    fn reference(&self) -> usize {
+        self.marks[self.group]
+            .iter()
+            .position(|&mark| mark == Mark::Keep)
+            .unwrap_or(0)
+    }
+
+    fn select_group(&mut self, group: usize) {
+        self.group = group;
+        self.member = 0;
+        self.scroll = 0;
+    }
+
+    fn set_mark(&mut self, mark: Mark) {
+        if mark == Mark::Move && !self.can_move {
+            self.status = "Cannot move without a destination directory (--destination)".into();
+            return;
+        }
+        self.marks[self.group][self.member] = mark;
+        self.status.clear();
+    }
+
+    /// Find the first group that removes members without keeping any.
+    fn find_keeperless_group(&self) -> Option<usize> {
+        self.marks
+            .iter()
+            .position(|marks| marks.iter().any(|mark| mark.is_removal()) && !marks.contains(&Mark::Keep))
+    }
+
+    fn count(&self, mark: Mark) -> usize {
+        self.marks.iter().flatten().filter(|&&m| m == mark).count()
+    }
+
+    /// Format a path relative to its input root,
+    /// keeping its end if it is longer than `width` characters.
+    fn fmt_path(&self, path: &Path, width: usize) -> String {
+        let path = match find_root(path, self.roots) {
+            Some(root) if root != path => path.strip_prefix(root).unwrap(), // root is a prefix of path
+            _ => path,
+        };
+        let text = path.display().to_string();
+        let len = text.chars().count();
+        match len > width {
+            true => std::iter::once('…')
+                .chain(text.chars().skip(len + 1 - width.max(1)))
+                .collect(),
+            false => text,
+        }
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let group = &self.groups[self.group];
+        if self.infos[self.group].is_none() {
+            self.infos[self.group] = Some(group.members.iter().map(|&(path, _)| MemberInfo::of(path)).collect());
+        }
+        let infos = self.infos[self.group].as_ref().unwrap(); // loaded above
+
+        let (cols, rows) = terminal::size()?;
+        let (cols, rows) = (cols as usize, rows as usize);
+        // header, blank line, table header, ..., blank line, status line, key help
+        let visible_rows = rows.saturating_sub(6).max(1);
+        if self.member < self.scroll {
+            self.scroll = self.member;
+        } else if self.member >= self.scroll + visible_rows {
+            self.scroll = self.member + 1 - visible_rows;
+        }
+
+        let truncate = |line: String| line.chars().take(cols).collect::<String>();
+        queue!(out, terminal::Clear(ClearType::All), cursor::MoveTo(0, 0))?;
+        queue!(
+            out,
+            SetAttribute(Attribute::Bold),
+            Print(truncate(format!(
+                "Group {}/{}: {} images, distances to {}",
+                self.group + 1,
+                self.groups.len(),
+                group.members.len(),
+                self.fmt_path(group.members[self.reference()].0, cols / 2),
+            ))),
+            SetAttribute(Attribute::Reset),
+            cursor::MoveToNextLine(2),
+            Print(truncate(format!(
+                "  {:<8}  {:>11}  {:>10}  {:<19}  {:<19}  {:>4}  Path",
+                "Mark", "Dimensions", "Size", "Modified", "EXIF date", "Dist"
+            ))),
+        )?;
+
+        let reference = self.reference();
+        for n in (self.scroll..group.members.len()).take(visible_rows) {
+            let info = &infos[n];
+            let dimensions = info.dimensions.map_or("?".into(), |(w, h)| format!("{}x{}", w, h));
+            let size = info.size.map_or("?".into(), |size| size.to_string());
+            let mtime = info.mtime.map_or("?".into(), |mtime| {
+                DateTime::<Local>::from(mtime).format("%Y-%m-%d %H:%M:%S").to_string()
+            });
+            let exif_date = info.exif_date.as_deref().unwrap_or("-");
+            let columns = format!(
+                "{} {:<8}  {:>11}  {:>10}  {:<19}  {:<19}  {:>4}  ",
+                if n == self.member { '>' } else { ' ' },
+                self.marks[self.group][n].label(),
+                dimensions,
+                size,
+                mtime,
+                exif_date,
+                group.dist(n, reference),
+            );
+            let path_width = cols.saturating_sub(columns.chars().count());
+            let line = truncate(columns + &self.fmt_path(group.members[n].0, path_width));
+            queue!(out, cursor::MoveToNextLine(1))?;
+            match n == self.member {
+                true => queue!(
+                    out,
+                    SetAttribute(Attribute::Reverse),
+                    Print(line),
+                    SetAttribute(Attribute::Reset)
+                )?,
+                false => queue!(out, Print(line))?,
+            }
+        }
+
+        queue!(
+            out,
+            cursor::MoveTo(0, rows.saturating_sub(2) as u16),
+            Print(truncate(self.status.clone())),
+            cursor::MoveTo(0, rows.saturating_sub(1) as u16),
+            SetAttribute(Attribute::Dim),
+            Print(truncate(KEY_HELP.into())),
+            SetAttribute(Attribute::Reset),
+        )?;
+        out.flush()
+    }
+
+    /// Handle a key press. Returns the outcome of the review if it has ended.
+    fn handle_key(&mut self, key: KeyEvent) -> Option<bool> {
+        let quit = key.code == KeyCode::Char('q')
+            || key.code == KeyCode::Esc
+            || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL));
+
+        // any key other than 'y' cancels applying
+        if self.confirming {
+            self.confirming = false;
+            self.status.clear();
+            return (key.code == KeyCode::Char('y')).then_some(true);
+        }
+
+        let group_len = self.groups[self.group].members.len();
+        match key.code {
+            _ if quit => return Some(false),
+            KeyCode::Up => self.member = self.member.saturating_sub(1),
+            KeyCode::Down => self.member = (self.member + 1).min(group_len - 1),
+            KeyCode::Left | KeyCode::PageUp => self.select_group(self.group.saturating_sub(1)),
+            KeyCode::Right | KeyCode::PageDown => self.select_group((self.group + 1).min(self.groups.len() - 1)),
+            KeyCode::Char('k') => self.set_mark(Mark::Keep),
+            KeyCode::Char('m') => self.set_mark(Mark::Move),
+            KeyCode::Char('d') => self.set_mark(Mark::Delete),
+            KeyCode::Char('x') => self.set_mark(Mark::NotDuplicate),
+            KeyCode::Char('a') => match self.find_keeperless_group() {
+                Some(group) => {
+                    self.select_group(group);
+                    self.status = "This group removes images without keeping any; mark one to keep".into();
+                }
+                None => {
+                    self.confirming = true;
+                    self.status = format!(
+                        "Apply: move {}, delete {} image(s)? (y/n)",
+                        self.count(Mark::Move),
+                        self.count(Mark::Delete)
+                    );
+                }
+            },
+            _ => {}
+        }
+        None
+    }
+}
+
+/// This function lets the user review the duplicate groups in an interactive
+/// terminal UI, starting from the given marks.
+///
+/// Moving is only offered if `can_move` is set (i.e. a destination is known).
+/// Before the marks are accepted, every group that removes any member
+/// must keep at least one.
+///
+/// Returns the final marks, or `None` if the user quit without applying.
+pub fn review_marks(
+    groups: &[DupGroup],
+    marks: Vec<Vec<Mark>>,
+    roots: &[PathBuf],
+    can_move: bool,
+) -> io::Result<Option<Vec<Vec<Mark>>>> {
+    if !io::stderr().is_terminal() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reviewing requires an interactive terminal",
+        ));
+    }
+
+    let mut review = Review {
+        groups,
+        roots,
+        marks,
+        infos: groups.iter().map(|_| None).collect(),
+        group: 0,
+        member: 0,
+        scroll: 0,
+        can_move,
+        confirming: false,
+        status: String::new(),
+    };
+
+    let _guard = TerminalGuard::enter()?;
+    let mut out = io::stderr();
+    loop {
+        review.draw(&mut out)?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match review.handle_key(key) {
+                Some(true) => return Ok(Some(review.marks)),
+                Some(false) => return Ok(None),
+                None => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! This module implements the generation of POSIX shell scripts,
+//! which perform the action chosen for each duplicate once reviewed and run.
+//!
+//! Every path is single-quoted, so the script is safe to run
+//! regardless of the characters in file names.
+//! Paths that are not valid UTF-8 cannot be written, and are refused.
+
+use std::path::Path;
+
+/// A single command in a generated script.
+#[derive(Debug, Clone)]
+pub enum ScriptCommand<'a> {
+    /// Move a file, unless the destination is taken.
+    Move { from: &'a Path, to: &'a Path },
+    /// Delete a file.
+    Remove { path: &'a Path },
+    /// Atomically replace a file with a hard link to `target`.
+    HardLink { path: &'a Path, target: &'a Path },
+    /// Atomically replace a file with a symbolic link containing `target`.
+    SymLink { path: &'a Path, target: &'a Path },
+}
+impl ScriptCommand<'_> {
+    /// Render the command as a line of shell script.
+    ///
+    /// Returns Err if any of its paths cannot be quoted (see [`quote`]).
+    pub fn render(&self) -> Result<String, String> {
+        let tmp_of = |path: &Path| {
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".img-dedup-link.tmp");
+            quote(Path::new(&tmp))
+        };
+        Ok(match self {
+            Self::Move { from, to } => format!("[ -e {1} ] || mv -- {0} {1}", quote(from)?, quote(to)?),
+            Self::Remove { path } => format!("rm -- {}", quote(path)?),
+            Self::HardLink { path, target } => format!(
+                "ln -- {} {tmp} && mv -f -- {tmp} {}",
+                quote(target)?,
+                quote(path)?,
+                tmp = tmp_of(path)?
+            ),
+            Self::SymLink { path, target } => format!(
+                "ln -s -- {} {tmp} && mv -f -- {tmp} {}",
+                quote(target)?,
+                quote(path)?,
+                tmp = tmp_of(path)?
+            ),
+        })
+    }
+}
+
+/// Quotes a path for a POSIX shell, by enclosing it in single quotes.
+/// Single quotes within are written as `'\''` (close, escaped quote, reopen).
+///
+/// Returns Err if the path is not valid UTF-8, since POSIX shells have no portable way
+/// to write arbitrary bytes, and a lossy conversion would name a different file.
+pub fn quote(path: &Path) -> Result<String, String> {
+    match path.to_str() {
+        Some(path_str) => Ok(format!("'{}'", path_str.replace('\'', r"'\''"))),
+        None => Err(format!(
+            "Cannot write {:?} in a script: its name is not valid UTF-8",
+            path
+        )),
+    }
+}
+
+/// The beginning of each script.
+pub const SCRIPT_HEADER: &str = "\
+#!/bin/sh
+# Duplicate image actions, written by img_dedup.
+# Review every line before running this script; remove or comment out any you disagree with.
+# Each command is preceded by a comment naming its group, the group's keeper,
+# and the hamming distance between the file and the keeper.
+# Existing files are never overwritten by moves.
+
+set -eu
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Have the shell print the quoted path back.
+    #[cfg(unix)]
+    fn shell_echo(quoted: &str) -> String {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf '%s' {}", quoted))
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn quote_plain() {
+        assert_eq!(
+            quote(Path::new("/a b/$HOME/*.jpg")),
+            Ok("'/a b/$HOME/*.jpg'".to_string())
+        );
+    }
+
+    #[test]
+    fn quote_single_quote() {
+        assert_eq!(quote(Path::new("it's.jpg")), Ok(r"'it'\''s.jpg'".to_string()));
+    }
+
+    #[test]
+    fn quote_newline() {
+        assert_eq!(quote(Path::new("a\nb.jpg")), Ok("'a\nb.jpg'".to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn quote_round_trips_through_shell() {
+        for name in ["it's.jpg", "a\nb.jpg", "\"$(rm -rf x)\"`x`\\.jpg", "'''", "-n"] {
+            assert_eq!(shell_echo(&quote(Path::new(name)).unwrap()), name);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn quote_refuses_non_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = Path::new(OsStr::from_bytes(b"caf\xe9.jpg"));
+        let err = quote(path).unwrap_err();
+        assert!(err.contains(r"caf\xE9.jpg"), "{}", err);
+        assert!(ScriptCommand::Remove { path }.render().is_err());
+    }
+}
@@ -1,47 +1,42 @@
 //! Each exported function in this module encapsulates
 //! all the tasks necessary for a single subcommand.
 
-use std::{collections::HashSet, path::PathBuf, process::exit};
+use std::{path::PathBuf, process::exit, sync::Mutex};
 
 use clap::ArgMatches;
 use crossbeam_channel::Receiver;
-use image::DynamicImage;
 use img_hash::ImageHash;
 
 use crate::{
-    io::get_filename_unchecked,
-    sub_ops::{filter_max_dist, log_pairwise_dists_sorted, move_all, pairwise_hash_dist, stream_hash},
+    cache::HashCache,
+    cluster::DupGroup,
+    io::LoadedImage,
+    keeper::list_duplicates,
+    sub_ops::{
+        apply_plan, choose_keepers, delete_all, find_similar_pairs, group_duplicates, link_all, move_all,
+        output_groups, output_hashes, output_pairs, review_groups, save_plan, save_report, save_script, stream_hash,
+        stream_hash_with_previews, undo_moves,
+    },
 };
 
+#[cfg(target_os = "linux")]
+use crate::sub_ops::share_extents;
+
 /// Corresponds to subcommand `hash`.
 pub fn hash_once(
-    imgs_rx: Receiver<(PathBuf, DynamicImage)>,
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
     concurrency: usize,
+    cache: Option<&Mutex<HashCache>>,
+    roots: &[PathBuf],
     sub_matches: &ArgMatches,
 ) -> Vec<(PathBuf, ImageHash)> {
     // compute hashes
-    let path_hash_pairs: Vec<_> = stream_hash(imgs_rx, concurrency, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
-
-    // format and log
-    const NAME_FMT_MAX_LEN: usize = 30; // file names longer than this get truncated
-    let name_hash_pairs: Vec<_> = path_hash_pairs
-        .iter()
-        .map(|(path, hash)| (get_filename_unchecked(&path).to_string(), hash))
-        .collect();
-    let name_fmt_len = name_hash_pairs
-        .iter()
-        .map(|(name, _)| name.len())
-        .max()
-        .unwrap_or(0)
-        .min(NAME_FMT_MAX_LEN);
-    for (name, hash) in name_hash_pairs {
-        let name_truncated_braced = format!("[{:.max_len$}]", name, max_len = NAME_FMT_MAX_LEN);
-        println!(
-            "  Img: {:<fmt_len$}  Hash: [{}]",
-            name_truncated_braced,
-            hash.to_base64(),
-            fmt_len = name_fmt_len + 2
-        );
+    let path_hash_pairs: Vec<_> = stream_hash(imgs_rx, concurrency, cache, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+
+    // output results
+    if let Err(e) = output_hashes(&path_hash_pairs, roots, sub_matches) {
+        eprintln!("Failed to output hashes: {}", e);
+        exit(1);
     }
 
     path_hash_pairs
@@ -49,53 +44,215 @@ pub fn hash_once(
 
 /// Corresponds to subcommand `scan-duplicates`.
 pub fn scan_duplicates(
-    imgs_rx: Receiver<(PathBuf, DynamicImage)>,
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
     concurrency: usize,
+    cache: Option<&Mutex<HashCache>>,
+    roots: &[PathBuf],
     sub_matches: &ArgMatches,
 ) -> Vec<(PathBuf, PathBuf, u32)> {
-    // compute hashes
-    let path_hash_pairs: Vec<_> = stream_hash(imgs_rx, concurrency, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+    // compute hashes, and thumbnails if previewed
+    let (path_hash_pairs, mut previewer) = stream_hash_with_previews(imgs_rx, concurrency, cache, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+
+    // find pairs within threshold
+    let similar_pairs = find_similar_pairs(&path_hash_pairs, concurrency, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+
+    // only duplicates are previewed, so drop the thumbnails of all other images
+    if let Some(previewer) = &mut previewer {
+        previewer.retain(&similar_pairs.iter().flat_map(|&(p0, p1, _)| [p0, p1]).collect());
+    }
+
+    // group duplicates, if shown, planned, scripted or reported
+    let view = sub_matches.value_of("view").unwrap(); // default provided by clap
+    let plan_or_script = sub_matches.is_present("plan") || sub_matches.is_present("script");
+    let groups = match view == "groups" || plan_or_script || sub_matches.is_present("report") {
+        true => Some(group_duplicates(&path_hash_pairs, &similar_pairs, sub_matches).unwrap()), // sub_matches should satisfy arg requirements
+        false => None,
+    };
+
+    // choose keepers, if planned, scripted or marked in the output
+    let marks_keepers =
+        !sub_matches.is_present("template") && matches!(sub_matches.value_of("format"), Some("fdupes" | "rmlint"));
+    let keepers = match &groups {
+        Some(groups) if plan_or_script || marks_keepers => {
+            Some(choose_keepers(groups, roots, sub_matches).unwrap()) // sub_matches should satisfy arg requirements
+        }
+        _ => None,
+    };
 
-    // compute pairwise hamming distances
-    let pairwise_distances = pairwise_hash_dist(&path_hash_pairs, concurrency);
+    // output each entry
+    let output_res = match view {
+        "pairs" => output_pairs(&path_hash_pairs, &similar_pairs, roots, previewer.as_ref(), sub_matches),
+        "groups" => output_groups(
+            groups.as_deref().unwrap(), // grouped above
+            keepers.as_deref(),
+            roots,
+            previewer.as_ref(),
+            sub_matches,
+        ),
+        _ => unreachable!("Cases should always cover all possible values"),
+    };
+    if let Err(e) = output_res {
+        eprintln!("Failed to output duplicates: {}", e);
+        exit(1);
+    }
 
-    // filter by threshold
-    let similar_pairs = filter_max_dist(&pairwise_distances, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+    // write plan and/or script for review
+    if let (Some(groups), Some(keepers)) = (&groups, &keepers) {
+        if sub_matches.is_present("plan") {
+            if let Err(e) = save_plan(groups, keepers, sub_matches) {
+                eprintln!("Failed to save plan: {}", e);
+                exit(1);
+            }
+        }
+        if sub_matches.is_present("script") {
+            if let Err(e) = save_script(groups, keepers, sub_matches) {
+                eprintln!("Failed to save script: {}", e);
+                exit(1);
+            }
+        }
+    }
 
-    // log each entry
-    log_pairwise_dists_sorted(&similar_pairs);
+    // write report for review in a browser
+    if let (Some(groups), true) = (&groups, sub_matches.is_present("report")) {
+        if let Err(e) = save_report(groups, concurrency, sub_matches) {
+            eprintln!("Failed to save report: {}", e);
+            exit(1);
+        }
+    }
 
     // ref -> owned
     similar_pairs
         .into_iter()
-        .map(|&(p0, p1, d)| (p0.into(), p1.into(), d))
+        .map(|(p0, p1, d)| (p0.into(), p1.into(), d))
         .collect()
 }
 
-/// Corresponds to subcommand `move-duplicates`.
-pub fn move_duplicates(imgs_rx: Receiver<(PathBuf, DynamicImage)>, concurrency: usize, sub_matches: &ArgMatches) {
-    use std::iter::once;
-
+/// This function runs the steps shared by the subcommands that act on duplicate groups:
+/// it computes hashes, finds similar pairs, groups them, and then passes the groups to `act`.
+///
+/// If no duplicates are found, `act` is not called.
+fn scan_groups(
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
+    concurrency: usize,
+    cache: Option<&Mutex<HashCache>>,
+    sub_matches: &ArgMatches,
+    act: impl FnOnce(&[DupGroup]),
+) {
     // compute hashes
-    let path_hash_pairs: Vec<_> = stream_hash(imgs_rx, concurrency, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
-
-    // compute pairwise hamming distances
-    let pairwise_distances = pairwise_hash_dist(&path_hash_pairs, concurrency);
+    let path_hash_pairs: Vec<_> = stream_hash(imgs_rx, concurrency, cache, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
 
-    // filter by threshold
-    let similar_pairs = filter_max_dist(&pairwise_distances, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+    // find pairs within threshold
+    let similar_pairs = find_similar_pairs(&path_hash_pairs, concurrency, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
 
-    // move all duplicates
-    if similar_pairs.len() == 0 {
+    // group duplicates
+    if similar_pairs.is_empty() {
         println!("No duplicate images found");
         return;
     }
-    let all_files: HashSet<_> = similar_pairs
-        .into_iter()
-        .flat_map(|&(p0, p1, _)| once(p0).chain(once(p1)))
-        .collect();
-    if let Err(e) = move_all(&all_files, sub_matches) {
-        println!("Failed to move duplicate images: {:?}", e);
+    let groups = group_duplicates(&path_hash_pairs, &similar_pairs, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+
+    act(&groups);
+}
+
+/// Corresponds to subcommand `move-duplicates`.
+pub fn move_duplicates(
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
+    concurrency: usize,
+    cache: Option<&Mutex<HashCache>>,
+    roots: &[PathBuf],
+    sub_matches: &ArgMatches,
+) {
+    scan_groups(imgs_rx, concurrency, cache, sub_matches, |groups| {
+        // move all duplicates except keepers
+        let keepers = choose_keepers(groups, roots, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+        let duplicates = list_duplicates(groups, &keepers);
+        if let Err(e) = move_all(&duplicates, sub_matches) {
+            eprintln!("Failed to move duplicate images: {}", e);
+            exit(1);
+        }
+    });
+}
+
+/// Corresponds to subcommand `delete-duplicates`.
+pub fn delete_duplicates(
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
+    concurrency: usize,
+    cache: Option<&Mutex<HashCache>>,
+    roots: &[PathBuf],
+    sub_matches: &ArgMatches,
+) {
+    scan_groups(imgs_rx, concurrency, cache, sub_matches, |groups| {
+        // delete all duplicates except keepers
+        let keepers = choose_keepers(groups, roots, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+        let duplicates = list_duplicates(groups, &keepers);
+        if let Err(e) = delete_all(&duplicates, sub_matches) {
+            eprintln!("Failed to delete duplicate images: {}", e);
+            exit(1);
+        }
+    });
+}
+
+/// Corresponds to subcommand `link-duplicates`.
+pub fn link_duplicates(
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
+    concurrency: usize,
+    cache: Option<&Mutex<HashCache>>,
+    roots: &[PathBuf],
+    sub_matches: &ArgMatches,
+) {
+    scan_groups(imgs_rx, concurrency, cache, sub_matches, |groups| {
+        // replace all duplicates except keepers with links
+        let keepers = choose_keepers(groups, roots, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+        let duplicates = list_duplicates(groups, &keepers);
+        link_all(&duplicates, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+    });
+}
+
+/// Corresponds to subcommand `reflink-duplicates`.
+#[cfg(target_os = "linux")]
+pub fn reflink_duplicates(
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
+    concurrency: usize,
+    cache: Option<&Mutex<HashCache>>,
+    roots: &[PathBuf],
+    sub_matches: &ArgMatches,
+) {
+    scan_groups(imgs_rx, concurrency, cache, sub_matches, |groups| {
+        // share storage between identical files
+        share_extents(groups, roots, sub_matches);
+    });
+}
+
+/// Corresponds to subcommand `review`.
+pub fn review_duplicates(
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
+    concurrency: usize,
+    cache: Option<&Mutex<HashCache>>,
+    roots: &[PathBuf],
+    sub_matches: &ArgMatches,
+) {
+    scan_groups(imgs_rx, concurrency, cache, sub_matches, |groups| {
+        // review groups, suggesting keepers, then act on the decisions
+        let keepers = choose_keepers(groups, roots, sub_matches).unwrap(); // sub_matches should satisfy arg requirements
+        if let Err(e) = review_groups(groups, &keepers, roots, sub_matches) {
+            eprintln!("Failed to review duplicate images: {}", e);
+            exit(1);
+        }
+    });
+}
+
+/// Corresponds to subcommand `apply`.
+pub fn apply(sub_matches: &ArgMatches) {
+    if let Err(e) = apply_plan(sub_matches) {
+        eprintln!("Failed to apply plan: {}", e);
+        exit(1);
+    }
+}
+
+/// Corresponds to subcommand `undo`.
+pub fn undo(sub_matches: &ArgMatches) {
+    if let Err(e) = undo_moves(sub_matches) {
+        eprintln!("Failed to undo moves: {}", e);
         exit(1);
     }
 }
@@ -7,154 +7,1616 @@
 //! and prints all relevant info to the console.
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
+    sync::Mutex,
 };
 
 use clap::ArgMatches;
 use crossbeam_channel::{unbounded, Receiver};
-use image::DynamicImage;
 use img_hash::ImageHash;
 use itertools::Itertools;
 
 use crate::{
-    cli_helper::{parse_algo, parse_hash_size},
-    compute::{calc_hashes, calc_pair_dist},
-    io::{get_filename_unchecked, test_write_to_dir},
+    cache::HashCache,
+    cli_helper::{
+        parse_algo, parse_collision_strategy, parse_hash_size, parse_keep_rule, parse_keep_rules_file, parse_link_type,
+        parse_linkage, parse_output_format, parse_plan_action, parse_preview_protocol, parse_preview_size,
+        parse_search_mode, parse_template,
+    },
+    cluster::{calc_groups, DupGroup},
+    compute::{calc_hashes, calc_similar_pairs, calc_similar_pairs_banded, calc_thumbnails, SearchMode},
+    io::{
+        check_dir_writable, file_checksum, find_root, get_filename_unchecked, has_same_content, is_same_file,
+        move_file, predict_link, replace_with_link, resolve_dest_path, symlink_target, test_write_to_dir,
+        CollisionStrategy, LinkOutcome, LoadedImage, MoveMethod,
+    },
+    journal::{read_journal, rewrite_journal, Journal, JournalEntry},
+    keeper::{choose_keeper, Duplicate},
+    output::{
+        full_path, write_fdupes, write_records, write_rmlint, FileStats, GroupMemberRecord, HashRecord, HashSettings,
+        OutputFormat, PairRecord, RmlintFile,
+    },
+    plan::{read_plan, write_plan, Plan, PlanAction, PlanFile, PlanGroup},
+    preview::{PreviewProtocol, Previewer},
+    report::{write_report_end, write_report_group, write_report_start, REPORT_THUMBNAIL_SIZE},
+    review::{review_marks, Mark},
+    script::{quote, ScriptCommand, SCRIPT_HEADER},
+    trash::{home_trash_dir, trash_file},
 };
 
+#[cfg(target_os = "linux")]
+use crate::reflink::dedupe_file;
+
 /// This function receives a list of loaded images via a channel,
 /// calculates their hashes, and collects them into a Vec.
 ///
+/// If a hash cache is provided, it is updated with the newly computed hashes
+/// and written back to disk.
+///
 /// Returns Err if the expected arguments (`algorithm`, `hash-size`)
 /// are not found in `sub_matches`.
 pub fn stream_hash(
-    imgs_rx: Receiver<(PathBuf, DynamicImage)>,
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
     concurrency: usize,
+    cache: Option<&Mutex<HashCache>>,
     sub_matches: &ArgMatches,
 ) -> Result<Vec<(PathBuf, ImageHash)>, String> {
-    println!("Computing perceptual hash...");
+    stream_hash_with_previews(imgs_rx, concurrency, cache, sub_matches).map(|(path_hash_pairs, _)| path_hash_pairs)
+}
+
+/// Hashed images, alongside a previewer if previews were requested.
+type HashesWithPreviews = (Vec<(PathBuf, ImageHash)>, Option<Previewer>);
+
+/// This function does the same as [`stream_hash`], but if previews
+/// are requested (`preview`), it also keeps a thumbnail of each decoded image
+/// (`preview-size`), and returns a [`Previewer`] to show them with.
+///
+/// Returns Err if the expected arguments (`algorithm`, `hash-size`)
+/// are not found in `sub_matches`.
+pub fn stream_hash_with_previews(
+    imgs_rx: Receiver<(PathBuf, LoadedImage)>,
+    concurrency: usize,
+    cache: Option<&Mutex<HashCache>>,
+    sub_matches: &ArgMatches,
+) -> Result<HashesWithPreviews, String> {
+    eprintln!("Computing perceptual hash...");
 
     // get algorithm option
-    let algo = parse_algo(
-        sub_matches
-            .value_of("algorithm")
-            .ok_or_else(|| "algorithm not specified")?,
-    )
-    .unwrap(); // validation provided by clap
+    let algo = parse_algo(sub_matches.value_of("algorithm").ok_or("algorithm not specified")?).unwrap(); // validation provided by clap
 
     // get hash size option
-    let hash_size = parse_hash_size(
-        sub_matches
-            .value_of("hash-size")
-            .ok_or_else(|| "hash-size not specified")?,
-    )
-    .unwrap(); // validation provided by clap
+    let hash_size = parse_hash_size(sub_matches.value_of("hash-size").ok_or("hash-size not specified")?).unwrap(); // validation provided by clap
+
+    // get preview options; a value is optional
+    let preview = match sub_matches.is_present("preview") {
+        true => {
+            let protocol = parse_preview_protocol(sub_matches.value_of("preview").unwrap_or("auto")).unwrap(); // validation provided by clap
+            let size = parse_preview_size(
+                sub_matches
+                    .value_of("preview-size")
+                    .ok_or("preview-size not specified")?,
+            )
+            .unwrap(); // validation provided by clap
+            Some((protocol.unwrap_or_else(PreviewProtocol::detect), size))
+        }
+        false => None,
+    };
 
     // create a unified reply channel for worker threads
     let (hashes_tx, hashes_rx) = unbounded();
+    let (thumbs_tx, thumbs_rx) = unbounded();
     // run calculations
-    calc_hashes(imgs_rx, hashes_tx, concurrency, algo, hash_size);
+    let thumbnails = preview.map(|(_, size)| (thumbs_tx, size));
+    calc_hashes(imgs_rx, hashes_tx, thumbnails, concurrency, algo, hash_size);
     // hash reply channel buffer => vec
     let path_hash_pairs: Vec<_> = hashes_rx.into_iter().collect();
+    let previewer = preview.map(|(protocol, size)| Previewer::new(protocol, size, thumbs_rx.into_iter().collect()));
 
-    println!(
+    eprintln!(
         "Finished computing perceptual hash for {} image(s)",
         path_hash_pairs.len()
     );
 
-    Ok(path_hash_pairs)
+    // update and persist cache
+    if let Some(cache) = cache {
+        let mut cache = cache.lock().unwrap();
+        eprintln!("Reused {} cached hash(es)", cache.hit_count());
+        for (path, hash) in path_hash_pairs.iter() {
+            cache.update(path, hash);
+        }
+        match cache.save() {
+            Ok(_) => eprintln!("Saved hash cache to [{}]", cache.file_path().display()),
+            Err(e) => eprintln!("Failed to save hash cache: {}", e),
+        }
+    }
+
+    Ok((path_hash_pairs, previewer))
 }
 
-/// This function is a simple wrapper around [`calc_pair_dist`],
+/// This function is a simple wrapper around [`calc_similar_pairs`]
+/// and [`calc_similar_pairs_banded`] depending on the search mode,
 /// with additional printing to the console.
-pub fn pairwise_hash_dist(path_hash_pairs: &[(PathBuf, ImageHash)], concurrency: usize) -> Vec<(&Path, &Path, u32)> {
-    println!("Computing pairwise hamming distances...");
+///
+/// Returns Err if the expected arguments (`threshold`, `search-mode`)
+/// are not found in `sub_matches`.
+pub fn find_similar_pairs<'a>(
+    path_hash_pairs: &'a [(PathBuf, ImageHash)],
+    concurrency: usize,
+    sub_matches: &ArgMatches,
+) -> Result<Vec<(&'a Path, &'a Path, u32)>, String> {
+    eprintln!("Computing pairwise hamming distances...");
+
+    // get threshold options
+    let threshold = sub_matches
+        .value_of("threshold")
+        .ok_or("threshold not specified")?
+        .parse::<u32>()
+        .unwrap(); // u32 parse validated by clap
+
+    // get search mode options
+    let search_mode = parse_search_mode(
+        sub_matches.value_of("search-mode").ok_or("search-mode not specified")?,
+        sub_matches.value_of("bands"),
+        threshold,
+    )
+    .unwrap(); // validation provided by clap
+    let fitted_mode = search_mode.fit_to_hashes(path_hash_pairs, threshold);
+    if fitted_mode != search_mode {
+        eprintln!("The hashes are too short to be split into more bands than the threshold; using exact search");
+    }
 
     // run calculations
-    let pairwise_distances = calc_pair_dist(&path_hash_pairs, concurrency);
+    let similar_pairs = match fitted_mode {
+        SearchMode::Exact => {
+            let (similar_pairs, dist_count) = calc_similar_pairs(path_hash_pairs, threshold, concurrency);
+            eprintln!("Finished computing {} hamming distances", dist_count);
+            similar_pairs
+        }
+        SearchMode::Banded(band_count) => {
+            let (similar_pairs, candidate_count, band_count) =
+                calc_similar_pairs_banded(path_hash_pairs, threshold, band_count, concurrency);
+            eprintln!(
+                "Finished computing hamming distances for {} candidate pairs sharing any of {} band(s)",
+                candidate_count, band_count
+            );
+            similar_pairs
+        }
+    };
 
-    println!(
-        "Finished computing hamming distances for {} pairs",
-        pairwise_distances.len()
+    eprintln!(
+        "Found {} similar pair(s) with a hamming distance of ≤{}",
+        similar_pairs.len(),
+        threshold
     );
 
-    pairwise_distances
+    Ok(similar_pairs)
 }
 
-/// This function takes a list of pairwise hamming distances,
-/// and filters out the pairs greater than the threshold.
+/// This function is a simple wrapper around [`calc_groups`],
+/// with additional printing to the console.
 ///
-/// Returns Err if the expected argument (`threshold`)
-/// is not found in `sub_matches`.
-pub fn filter_max_dist<'a>(
-    pairwise_distances: &'a [(&Path, &Path, u32)],
+/// Returns Err if the expected arguments (`threshold`, `linkage`)
+/// are not found in `sub_matches`.
+pub fn group_duplicates<'a>(
+    path_hash_pairs: &'a [(PathBuf, ImageHash)],
+    similar_pairs: &[(&Path, &Path, u32)],
     sub_matches: &ArgMatches,
-) -> Result<Vec<&'a (&'a Path, &'a Path, u32)>, String> {
-    println!("Filtering pairwise hamming distances...");
-
+) -> Result<Vec<DupGroup<'a>>, String> {
     // get threshold options
     let threshold = sub_matches
         .value_of("threshold")
-        .ok_or_else(|| "threshold not specified")?
+        .ok_or("threshold not specified")?
         .parse::<u32>()
         .unwrap(); // u32 parse validated by clap
 
-    // filter
-    let similar_pairs: Vec<_> = pairwise_distances
-        .into_iter()
-        .filter(|(_, _, dist)| *dist <= threshold)
-        .collect();
+    // get linkage options
+    let linkage_name = sub_matches.value_of("linkage").ok_or("linkage not specified")?;
+    let linkage = parse_linkage(linkage_name).unwrap(); // validation provided by clap
 
-    println!(
-        "Found {} similar pair(s) with a hamming distance of ≤{}",
-        similar_pairs.len(),
-        threshold
+    eprintln!("Grouping similar pairs with {} linkage...", linkage_name);
+
+    // run calculations
+    let groups = calc_groups(path_hash_pairs, similar_pairs, linkage, threshold);
+
+    eprintln!(
+        "Formed {} group(s) containing {} image(s)",
+        groups.len(),
+        groups.iter().map(|g| g.members.len()).sum::<usize>()
     );
 
-    Ok(similar_pairs)
+    Ok(groups)
+}
+
+/// This function formats a path relative to the input root it was loaded from.
+fn fmt_with_root(path: &Path, roots: &[PathBuf]) -> String {
+    match find_root(path, roots) {
+        // the root is a directory
+        Some(root) if root != path => {
+            let rel_path = path.strip_prefix(root).unwrap(); // root is a prefix of path
+            format!("[{}] in [{}]", rel_path.display(), root.display())
+        }
+        // the root is the file itself
+        _ => format!("[{}]", path.display()),
+    }
 }
 
 /// This function takes a list of pairwise hamming distances
 /// and log them to the console formatted, sorted by their
 /// hamming distances in ascending order.
-pub fn log_pairwise_dists_sorted(pairs: &[&(&Path, &Path, u32)]) {
-    for &&(p0, p1, dist) in pairs.iter().sorted_by_key(|(_, _, dist)| *dist) {
-        let n0 = get_filename_unchecked(p0);
-        let n1 = get_filename_unchecked(p1);
-        println!("  [{}] - [{}]  Distance: {}", n0, n1, dist);
+///
+/// Each path is shown relative to the input root it was loaded from.
+/// If a previewer is given, each pair is followed by thumbnails of both images.
+pub fn log_pairwise_dists_sorted(pairs: &[(&Path, &Path, u32)], roots: &[PathBuf], previewer: Option<&Previewer>) {
+    for &(p0, p1, dist) in pairs.iter().sorted_by_key(|(_, _, dist)| *dist) {
+        let n0 = fmt_with_root(p0, roots);
+        let n1 = fmt_with_root(p1, roots);
+        println!("  {} - {}  Distance: {}", n0, n1, dist);
+        if let Some(previewer) = previewer {
+            show_preview(previewer, &[p0, p1]);
+        }
     }
 }
 
-/// This function takes a set of paths to files
-/// and move them to the specified destination directory.
+/// This function takes a list of duplicate groups and log them
+/// to the console formatted, listing each member's distance
+/// to the group's representative.
+///
+/// Each path is shown relative to the input root it was loaded from.
+/// If a previewer is given, each group is followed by thumbnails of its members.
+pub fn log_groups(groups: &[DupGroup], roots: &[PathBuf], previewer: Option<&Previewer>) {
+    for (id, group) in groups.iter().enumerate() {
+        println!(
+            "  Group {} ({} images, diameter {}):",
+            id + 1,
+            group.members.len(),
+            group.diameter()
+        );
+        for (n, &(path, dist)) in group.members.iter().enumerate() {
+            match n {
+                0 => println!("    {}  Representative", fmt_with_root(path, roots)),
+                _ => println!("    {}  Distance: {}", fmt_with_root(path, roots), dist),
+            }
+        }
+        if let Some(previewer) = previewer {
+            let paths: Vec<_> = group.members.iter().map(|&(path, _)| path).collect();
+            show_preview(previewer, &paths);
+        }
+    }
+}
+
+/// This function shows thumbnails of images on stdout, in the given order.
+/// Failing to do so is only logged, since previews are merely informational.
+fn show_preview(previewer: &Previewer, paths: &[&Path]) {
+    if let Err(e) = previewer.show(&mut std::io::stdout().lock(), paths) {
+        eprintln!("Failed to show preview: {}", e);
+    }
+}
+
+/// This function logs the hash of each image to the console
+/// as a table, truncating long file names.
+pub fn log_hashes(path_hash_pairs: &[(PathBuf, ImageHash)]) {
+    const NAME_FMT_MAX_LEN: usize = 30; // file names longer than this get truncated
+    let name_hash_pairs: Vec<_> = path_hash_pairs
+        .iter()
+        .map(|(path, hash)| (get_filename_unchecked(path).to_string(), hash))
+        .collect();
+    let name_fmt_len = name_hash_pairs
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .min(NAME_FMT_MAX_LEN);
+    for (name, hash) in name_hash_pairs {
+        let name_truncated_braced = format!("[{:.max_len$}]", name, max_len = NAME_FMT_MAX_LEN);
+        println!(
+            "  Img: {:<fmt_len$}  Hash: [{}]",
+            name_truncated_braced,
+            hash.to_base64(),
+            fmt_len = name_fmt_len + 2
+        );
+    }
+}
+
+/// This function reads the selected output format and hash settings.
+/// A template (`template`) takes precedence over a fixed format (`format`).
 ///
-/// If an error is encountered while moving an individual file,
-/// it will be logged to console and skipped.
+/// Returns Err if the expected arguments (`format`, `algorithm`, `hash-size`)
+/// are not found in `sub_matches`.
+fn get_output_options<'a>(sub_matches: &'a ArgMatches) -> Result<(OutputFormat, HashSettings<'a>), String> {
+    let format = match sub_matches.value_of("template") {
+        Some(template) => OutputFormat::Template(parse_template(template).unwrap()), // validation provided by clap
+        None => parse_output_format(sub_matches.value_of("format").ok_or("format not specified")?).unwrap(), // validation provided by clap
+    };
+    let settings = HashSettings {
+        algorithm: sub_matches.value_of("algorithm").ok_or("algorithm not specified")?,
+        hash_size: parse_hash_size(sub_matches.value_of("hash-size").ok_or("hash-size not specified")?).unwrap(), // validation provided by clap
+    };
+    Ok((format, settings))
+}
+
+/// The full path of the input root a file was loaded from, for machine-readable output.
+fn root_of(path: &Path, roots: &[PathBuf]) -> Result<Option<String>, String> {
+    find_root(path, roots).map(full_path).transpose()
+}
+
+/// This function outputs the hash of each image in the selected format,
+/// alongside the input root it was loaded from.
+///
+/// Returns Err if the expected arguments (`format`, `algorithm`, `hash-size`)
+/// are not found in `sub_matches`, or if writing to stdout fails.
+pub fn output_hashes(
+    path_hash_pairs: &[(PathBuf, ImageHash)],
+    roots: &[PathBuf],
+    sub_matches: &ArgMatches,
+) -> Result<(), String> {
+    let (format, settings) = get_output_options(sub_matches)?;
+    if format == OutputFormat::Text {
+        log_hashes(path_hash_pairs);
+        return Ok(());
+    }
+
+    let records: Vec<_> = path_hash_pairs
+        .iter()
+        .map(|(path, hash)| {
+            let FileStats { size, width, height } = FileStats::of(path);
+            Ok(HashRecord {
+                path: full_path(path)?,
+                root: root_of(path, roots)?,
+                size,
+                width,
+                height,
+                hash: hash.to_base64(),
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    write_records(&mut std::io::stdout().lock(), &format, &settings, "images", &records).map_err(|e| e.to_string())
+}
+
+/// This function outputs similar pairs in the selected format,
+/// sorted by their hamming distances in ascending order.
+///
+/// Returns Err if the expected arguments (`format`, `algorithm`, `hash-size`)
+/// are not found in `sub_matches`, if the format only supports groups,
+/// or if writing to stdout fails.
+pub fn output_pairs(
+    path_hash_pairs: &[(PathBuf, ImageHash)],
+    similar_pairs: &[(&Path, &Path, u32)],
+    roots: &[PathBuf],
+    previewer: Option<&Previewer>,
+    sub_matches: &ArgMatches,
+) -> Result<(), String> {
+    let (format, settings) = get_output_options(sub_matches)?;
+    if format == OutputFormat::Text {
+        log_pairwise_dists_sorted(similar_pairs, roots, previewer);
+        return Ok(());
+    }
+    if previewer.is_some() {
+        eprintln!("Previews are only shown in the text format");
+    }
+    if let OutputFormat::Fdupes | OutputFormat::Rmlint = format {
+        return Err("The fdupes and rmlint formats only list groups; use --view groups".into());
+    }
+
+    let hashes: HashMap<_, _> = path_hash_pairs
+        .iter()
+        .map(|(path, hash)| (path.as_path(), hash))
+        .collect();
+    let records: Vec<_> = similar_pairs
+        .iter()
+        .sorted_by_key(|(_, _, dist)| *dist)
+        .map(|&(p0, p1, dist)| {
+            let (stats0, stats1) = (FileStats::of(p0), FileStats::of(p1));
+            Ok(PairRecord {
+                path0: full_path(p0)?,
+                root0: root_of(p0, roots)?,
+                size0: stats0.size,
+                width0: stats0.width,
+                height0: stats0.height,
+                hash0: hashes[p0].to_base64(),
+                path1: full_path(p1)?,
+                root1: root_of(p1, roots)?,
+                size1: stats1.size,
+                width1: stats1.width,
+                height1: stats1.height,
+                hash1: hashes[p1].to_base64(),
+                distance: dist,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    write_records(&mut std::io::stdout().lock(), &format, &settings, "pairs", &records).map_err(|e| e.to_string())
+}
+
+/// This function outputs duplicate groups in the selected format,
+/// one record per group member.
 ///
-/// Returns Err if the expected argument (`destination`)
+/// The fdupes and rmlint formats also mark the keeper of each group,
+/// so they require `keepers` (see `choose_keepers`).
+///
+/// Returns Err if the expected arguments (`format`, `algorithm`, `hash-size`)
+/// are not found in `sub_matches`, if keepers are required but not given,
+/// or if writing to stdout fails.
+pub fn output_groups(
+    groups: &[DupGroup],
+    keepers: Option<&[usize]>,
+    roots: &[PathBuf],
+    previewer: Option<&Previewer>,
+    sub_matches: &ArgMatches,
+) -> Result<(), String> {
+    let (format, settings) = get_output_options(sub_matches)?;
+    if format != OutputFormat::Text && previewer.is_some() {
+        eprintln!("Previews are only shown in the text format");
+    }
+    match format {
+        OutputFormat::Text => {
+            log_groups(groups, roots, previewer);
+            return Ok(());
+        }
+        OutputFormat::Fdupes | OutputFormat::Rmlint => {
+            let keepers = keepers.ok_or("keepers not chosen")?;
+            return output_groups_as_other_tool(groups, keepers, &format, &settings);
+        }
+        _ => {}
+    }
+
+    let records: Vec<_> = groups
+        .iter()
+        .enumerate()
+        .flat_map(|(id, group)| {
+            group
+                .members
+                .iter()
+                .zip(group.hashes.iter())
+                .enumerate()
+                .map(move |(n, (&(path, dist), hash))| {
+                    let FileStats { size, width, height } = FileStats::of(path);
+                    Ok(GroupMemberRecord {
+                        group: id + 1,
+                        path: full_path(path)?,
+                        root: root_of(path, roots)?,
+                        size,
+                        width,
+                        height,
+                        hash: hash.to_base64(),
+                        distance: dist,
+                        representative: n == 0,
+                    })
+                })
+        })
+        .collect::<Result<_, String>>()?;
+    write_records(&mut std::io::stdout().lock(), &format, &settings, "members", &records).map_err(|e| e.to_string())
+}
+
+/// This function outputs duplicate groups in the format of another
+/// duplicate finder (fdupes or rmlint), with the keeper of each group
+/// listed first (and marked as the original in rmlint's format).
+fn output_groups_as_other_tool(
+    groups: &[DupGroup],
+    keepers: &[usize],
+    format: &OutputFormat,
+    settings: &HashSettings,
+) -> Result<(), String> {
+    // keeper first, then the other members in their group order
+    let ordered_groups: Vec<Vec<_>> = groups
+        .iter()
+        .zip(keepers.iter())
+        .map(|(group, &keeper)| {
+            std::iter::once(keeper)
+                .chain((0..group.members.len()).filter(|&n| n != keeper))
+                .map(|n| group.members[n].0)
+                .collect()
+        })
+        .collect();
+
+    let mut out = std::io::stdout().lock();
+    let write_res = match format {
+        OutputFormat::Fdupes => write_fdupes(&mut out, &ordered_groups),
+        OutputFormat::Rmlint => {
+            let files = groups
+                .iter()
+                .zip(ordered_groups.iter())
+                .flat_map(|(group, paths)| {
+                    // rmlint groups files by checksum, so all members share the representative's hash
+                    let checksum: String = group.hashes[0]
+                        .as_bytes()
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect();
+                    paths
+                        .iter()
+                        .enumerate()
+                        .map(move |(n, path)| RmlintFile::of(path, &checksum, n == 0))
+                })
+                .collect::<Result<_, String>>()?;
+            write_rmlint(&mut out, settings, files)
+        }
+        _ => unreachable!("Only formats of other tools are handled here"),
+    };
+    write_res.map_err(|e| e.to_string())
+}
+
+/// This function chooses the keeper of each duplicate group,
+/// and logs them to stderr.
+///
+/// Returns the index of the keeper in each group's members.
+///
+/// A rules file (`keep-rules`) takes precedence over a single policy (`keep`).
+///
+/// Returns Err if the expected argument (`keep`)
 /// is not found in `sub_matches`.
-pub fn move_all(files: &HashSet<&Path>, sub_matches: &ArgMatches) -> Result<(), String> {
-    use std::fs::rename as mv;
+pub fn choose_keepers(groups: &[DupGroup], roots: &[PathBuf], sub_matches: &ArgMatches) -> Result<Vec<usize>, String> {
+    // get keeper options
+    let rules = match sub_matches.value_of("keep-rules") {
+        Some(rules_file) => parse_keep_rules_file(rules_file).unwrap(), // validation provided by clap
+        None => vec![parse_keep_rule(
+            sub_matches.value_of("keep").ok_or("keep not specified")?,
+            sub_matches.value_of("preferred-format"),
+        )
+        .unwrap()], // validation provided by clap
+    };
+
+    eprintln!(
+        "Choosing the image to keep in each group by {}...",
+        rules.iter().map(|rule| rule.describe()).join(", then ")
+    );
 
+    let keepers: Vec<_> = groups
+        .iter()
+        .enumerate()
+        .map(|(id, group)| {
+            let (keeper, decisive_rule) = choose_keeper(group, &rules);
+            let reason = match decisive_rule {
+                Some(rule) => rule.describe(),
+                None => "all rules tied, smallest path".to_string(),
+            };
+            eprintln!(
+                "  Group {}: keeping {}  Decided by: {}",
+                id + 1,
+                fmt_with_root(group.members[keeper].0, roots),
+                reason
+            );
+            keeper
+        })
+        .collect();
+
+    Ok(keepers)
+}
+
+/// The file name of the journal in the destination directory,
+/// if not otherwise specified.
+const DEFAULT_JOURNAL_NAME: &str = "img_dedup-journal.jsonl";
+
+/// This function takes a list of duplicates
+/// and move them to the specified destination directory.
+///
+/// Files are moved in the order they are listed.
+/// If a file with the same name already exists at the destination
+/// (or is about to be moved there), the collision strategy is applied.
+/// Existing files are never replaced unless the strategy is `overwrite`.
+///
+/// If the destination is on another filesystem, files are copied,
+/// verified, and only then deleted from their original locations.
+///
+/// Each move is recorded in a journal, so that it can be undone later.
+///
+/// The result of each file is logged to the console. If an error is
+/// encountered while moving an individual file, it is skipped.
+///
+/// In a dry run (`dry-run`), the planned moves are only logged,
+/// with their final names at the destination; nothing is created or moved.
+///
+/// Returns Err if the expected arguments (`destination`, `on-collision`)
+/// are not found in `sub_matches`, if the journal cannot be opened,
+/// or if aborted due to a collision.
+pub fn move_all(duplicates: &[Duplicate], sub_matches: &ArgMatches) -> Result<(), String> {
     // get destination option
     let dest_dir = sub_matches
         .value_of("destination")
-        .ok_or_else(|| "move destination directory not specified")?;
+        .ok_or("move destination directory not specified")?;
+    let dest_dir = Path::new(dest_dir);
+
+    // get collision options
+    let strategy = parse_collision_strategy(
+        sub_matches
+            .value_of("on-collision")
+            .ok_or("on-collision not specified")?,
+    )
+    .unwrap(); // validation provided by clap
+
+    // get dry run option
+    let dry_run = sub_matches.is_present("dry-run");
+
+    // test write to destination directory, without creating anything in a dry run
+    match dry_run {
+        true => {
+            if !check_dir_writable(dest_dir).map_err(|e| e.to_string())? {
+                println!(
+                    "Destination [{}] does not exist, and would be created",
+                    dest_dir.display()
+                );
+            }
+        }
+        false => test_write_to_dir(dest_dir).map_err(|e| e.to_string())?,
+    }
+
+    // open journal, defaulting to a file in the destination directory
+    let journal_path = match sub_matches.value_of("journal") {
+        Some(path) => PathBuf::from(path),
+        None => dest_dir.join(DEFAULT_JOURNAL_NAME),
+    };
+    let mut journal = match dry_run {
+        true => None,
+        false => Some(Journal::open(&journal_path).map_err(|e| format!("Cannot open journal: {}", e))?),
+    };
+
+    match dry_run {
+        true => println!(
+            "Planning to move {} image(s) into [{}]...",
+            duplicates.len(),
+            dest_dir.display()
+        ),
+        false => println!("Moving {} image(s) into [{}]...", duplicates.len(), dest_dir.display()),
+    }
+
+    // move all, retaining original filenames where possible
+    let mut claimed = HashSet::new();
+    let (mut moved_count, mut moved_bytes) = (0, 0);
+    for dup in duplicates.iter() {
+        let from_path = dup.path;
+        let dest_path = match resolve_dest_path(from_path, dest_dir, strategy, &mut claimed)? {
+            Some(path) => path,
+            None => {
+                println!("  Skipped {:?}: name already taken at destination", from_path);
+                continue;
+            }
+        };
+        if dest_path.file_name() != from_path.file_name() {
+            match dry_run {
+                true => println!("  Would rename {:?} to {:?} to avoid a collision", from_path, dest_path),
+                false => println!("  Renaming {:?} to {:?} to avoid a collision", from_path, dest_path),
+            }
+        }
+
+        let overwrite = strategy == CollisionStrategy::Overwrite;
+        let size = std::fs::metadata(from_path).map_or(0, |meta| meta.len());
+        if dry_run {
+            match overwrite && dest_path.exists() {
+                true => println!(
+                    "  Would move {:?} -> {:?}, replacing the existing file",
+                    from_path, dest_path
+                ),
+                false => println!("  Would move {:?} -> {:?}", from_path, dest_path),
+            }
+            moved_count += 1;
+            moved_bytes += size;
+            continue;
+        }
+        let method = match move_file(from_path, &dest_path, overwrite) {
+            Ok(method) => method,
+            Err(e) => {
+                println!("  Failed to move {:?}: {}", from_path, e);
+                continue;
+            }
+        };
+        moved_count += 1;
+        moved_bytes += size;
+        match method {
+            MoveMethod::Renamed => println!("  Moved {:?} -> {:?}", from_path, dest_path),
+            MoveMethod::CopiedAcrossFilesystems => {
+                println!("  Copied, verified and removed {:?} -> {:?}", from_path, dest_path)
+            }
+        }
+
+        let entry = JournalEntry::now(from_path, &dest_path, dup.group_id, dup.distance);
+        if let Some(Err(e)) = journal.as_mut().map(|journal| journal.record(&entry)) {
+            println!("  Failed to record the move in the journal: {}", e);
+        }
+    }
+
+    match journal {
+        Some(journal) => {
+            println!(
+                "Moved {} of {} image(s), {} bytes in total",
+                moved_count,
+                duplicates.len(),
+                moved_bytes
+            );
+            println!("Moves recorded in journal [{}]", journal.path().display());
+        }
+        None => println!(
+            "Would move {} of {} image(s), {} bytes in total; nothing was changed (dry run)",
+            moved_count,
+            duplicates.len(),
+            moved_bytes
+        ),
+    }
+
+    Ok(())
+}
+
+/// This function reads a journal, and moves each recorded file
+/// back to its original path, in the reverse order they were moved.
+///
+/// A file is not restored if its original path is taken (a conflict),
+/// or if it is no longer at its recorded destination (missing).
+/// Those entries are kept in the journal, so that the undo can be retried
+/// after resolving them; the journal is deleted once fully restored.
+///
+/// In a dry run (`dry-run`), the files are only checked,
+/// and neither they nor the journal are modified.
+///
+/// Returns Err if the expected argument (`journal`)
+/// is not found in `sub_matches`, or if the journal cannot be read.
+pub fn undo_moves(sub_matches: &ArgMatches) -> Result<(), String> {
+    use std::fs::create_dir_all as mkdir;
+
+    // get journal option
+    let journal_path = Path::new(sub_matches.value_of("journal").ok_or("journal not specified")?);
+    let entries = read_journal(journal_path).map_err(|e| format!("Cannot read journal: {}", e))?;
+
+    // get dry run option
+    let dry_run = sub_matches.is_present("dry-run");
+
+    match dry_run {
+        true => println!(
+            "Planning to restore {} file(s) recorded in [{}]...",
+            entries.len(),
+            journal_path.display()
+        ),
+        false => println!(
+            "Restoring {} file(s) recorded in [{}]...",
+            entries.len(),
+            journal_path.display()
+        ),
+    }
+
+    let mut remaining = vec![];
+    let (mut restored_count, mut conflict_count, mut missing_count, mut failed_count) = (0, 0, 0, 0);
+    for entry in entries.into_iter().rev() {
+        let JournalEntry {
+            source, destination, ..
+        } = &entry;
+        if !destination.exists() {
+            println!("  Missing {:?}: no longer at its recorded destination", destination);
+            missing_count += 1;
+        } else if source.exists() {
+            println!("  Conflict {:?}: original path is taken", source);
+            conflict_count += 1;
+        } else if dry_run {
+            println!("  Would restore {:?} -> {:?}", destination, source);
+            restored_count += 1;
+            continue;
+        } else {
+            let restore_res = source
+                .parent()
+                .map_or(Ok(()), mkdir)
+                .and_then(|_| move_file(destination, source, false));
+            match restore_res {
+                Ok(_) => {
+                    println!("  Restored {:?} -> {:?}", destination, source);
+                    restored_count += 1;
+                    continue;
+                }
+                Err(e) => {
+                    println!("  Failed to restore {:?}: {}", destination, e);
+                    failed_count += 1;
+                }
+            }
+        }
+        remaining.push(entry);
+    }
+
+    if dry_run {
+        println!(
+            "Would restore {} file(s); {} conflict(s), {} missing; nothing was changed (dry run)",
+            restored_count, conflict_count, missing_count
+        );
+        return Ok(());
+    }
+    println!(
+        "Restored {} file(s); {} conflict(s), {} missing, {} failed",
+        restored_count, conflict_count, missing_count, failed_count
+    );
+
+    // keep unrestored entries, in their original order
+    remaining.reverse();
+    rewrite_journal(journal_path, &remaining).map_err(|e| format!("Cannot update journal: {}", e))?;
+    match remaining.len() {
+        0 => println!("All files restored; removed journal"),
+        n => println!("Partially restored; {} entries remain in the journal for a retry", n),
+    }
+
+    Ok(())
+}
+
+/// This function deletes a list of duplicates.
+///
+/// By default, files are moved to the trash (following the FreeDesktop.org
+/// trash specification), so that they can be restored from a file manager.
+/// If `permanent` is set, files are deleted outright instead.
+///
+/// The result of each file is logged to the console. If an error is
+/// encountered while deleting an individual file, it is skipped.
+///
+/// In a dry run (`dry-run`), the planned deletions are only logged.
+///
+/// Returns Err if the trash directory cannot be located.
+pub fn delete_all(duplicates: &[Duplicate], sub_matches: &ArgMatches) -> Result<(), String> {
+    // get deletion options
+    let permanent = sub_matches.is_present("permanent");
+    let trash_dir = match permanent {
+        true => None,
+        false => Some(home_trash_dir()?),
+    };
+
+    // get dry run option
+    let dry_run = sub_matches.is_present("dry-run");
+
+    match (&trash_dir, dry_run) {
+        (Some(dir), false) => println!(
+            "Moving {} image(s) to the trash [{}]...",
+            duplicates.len(),
+            dir.display()
+        ),
+        (Some(dir), true) => println!(
+            "Planning to move {} image(s) to the trash [{}]...",
+            duplicates.len(),
+            dir.display()
+        ),
+        (None, false) => println!("Permanently deleting {} image(s)...", duplicates.len()),
+        (None, true) => println!("Planning to permanently delete {} image(s)...", duplicates.len()),
+    }
+
+    let (mut deleted_count, mut deleted_bytes) = (0, 0);
+    for dup in duplicates.iter() {
+        let path = dup.path;
+        let size = std::fs::metadata(path).map_or(0, |meta| meta.len());
+        match (&trash_dir, dry_run) {
+            (Some(_), true) => println!("  Would trash {:?}", path),
+            (None, true) => println!("  Would delete {:?}", path),
+            (Some(dir), false) => match trash_file(path, dir) {
+                Ok((_, MoveMethod::Renamed)) => println!("  Trashed {:?}", path),
+                Ok((_, MoveMethod::CopiedAcrossFilesystems)) => {
+                    println!("  Trashed {:?} (copied, verified and removed)", path)
+                }
+                Err(e) => {
+                    println!("  Failed to trash {:?}: {}", path, e);
+                    continue;
+                }
+            },
+            (None, false) => match std::fs::remove_file(path) {
+                Ok(_) => println!("  Deleted {:?}", path),
+                Err(e) => {
+                    println!("  Failed to delete {:?}: {}", path, e);
+                    continue;
+                }
+            },
+        }
+        deleted_count += 1;
+        deleted_bytes += size;
+    }
+
+    let total = duplicates.len();
+    match (trash_dir, dry_run) {
+        (Some(_), false) => println!(
+            "Trashed {} of {} image(s), {} bytes in total",
+            deleted_count, total, deleted_bytes
+        ),
+        (None, false) => println!(
+            "Permanently deleted {} of {} image(s), {} bytes in total",
+            deleted_count, total, deleted_bytes
+        ),
+        (Some(_), true) => println!(
+            "Would trash {} of {} image(s), {} bytes in total; nothing was changed (dry run)",
+            deleted_count, total, deleted_bytes
+        ),
+        (None, true) => println!(
+            "Would permanently delete {} of {} image(s), {} bytes in total; nothing was changed (dry run)",
+            deleted_count, total, deleted_bytes
+        ),
+    }
+
+    Ok(())
+}
+
+/// This function replaces each duplicate with a link to its group's keeper,
+/// so that its path keeps resolving while its storage is freed.
+///
+/// Each replacement is atomic: the link is created under a temporary name,
+/// and then renamed over the duplicate.
+///
+/// The result of each file is logged to the console. If an error is
+/// encountered while linking an individual file, it is skipped.
+/// Storage is only freed if the duplicate has no other hard links.
+///
+/// Duplicates whose content differs from their keeper (i.e. that merely look alike)
+/// are skipped, since replacing them would lose their content.
+///
+/// In a dry run (`dry-run`), the planned links are only logged.
+///
+/// Returns Err if the expected argument (`link-type`)
+/// is not found in `sub_matches`.
+pub fn link_all(duplicates: &[Duplicate], sub_matches: &ArgMatches) -> Result<(), String> {
+    // get link options
+    let link_type = parse_link_type(sub_matches.value_of("link-type").ok_or("link-type not specified")?).unwrap(); // validation provided by clap
+
+    // get dry run option
+    let dry_run = sub_matches.is_present("dry-run");
+
+    match dry_run {
+        true => println!(
+            "Planning to replace {} image(s) with links to their keepers...",
+            duplicates.len()
+        ),
+        false => println!("Replacing {} image(s) with links to their keepers...", duplicates.len()),
+    }
+
+    let (mut linked_count, mut bytes_saved) = (0, 0);
+    for dup in duplicates.iter() {
+        let (path, keeper) = (dup.path, dup.keeper);
+        let freed_size = match std::fs::metadata(path) {
+            Ok(meta) => freed_size_on_unlink(&meta),
+            Err(e) => {
+                println!("  Failed to link {:?}: {}", path, e);
+                continue;
+            }
+        };
+        match has_same_content(path, keeper) {
+            Ok(true) => {}
+            Ok(false) => {
+                println!("  Skipped {:?}: its content differs from {:?}", path, keeper);
+                continue;
+            }
+            Err(e) => {
+                println!("  Failed to link {:?}: {}", path, e);
+                continue;
+            }
+        }
+        let link_res = match dry_run {
+            true => predict_link(path, keeper, link_type),
+            false => replace_with_link(path, keeper, link_type),
+        };
+        match link_res {
+            Ok(LinkOutcome::Unchanged) => {
+                println!("  Skipped {:?}: already a hard link to {:?}", path, keeper);
+                continue;
+            }
+            Ok(LinkOutcome::Hard) if dry_run => println!("  Would hard link {:?} -> {:?}", path, keeper),
+            Ok(LinkOutcome::Symbolic) if dry_run => println!("  Would symlink {:?} -> {:?}", path, keeper),
+            Ok(LinkOutcome::Hard) => println!("  Hard linked {:?} -> {:?}", path, keeper),
+            Ok(LinkOutcome::Symbolic) => println!("  Symlinked {:?} -> {:?}", path, keeper),
+            Err(e) => {
+                println!("  Failed to link {:?}: {}", path, e);
+                continue;
+            }
+        }
+        linked_count += 1;
+        bytes_saved += freed_size;
+    }
+
+    match dry_run {
+        true => println!(
+            "Would replace {} of {} image(s) with links, saving {} bytes; nothing was changed (dry run)",
+            linked_count,
+            duplicates.len(),
+            bytes_saved
+        ),
+        false => println!(
+            "Replaced {} of {} image(s) with links, saving {} bytes",
+            linked_count,
+            duplicates.len(),
+            bytes_saved
+        ),
+    }
+
+    Ok(())
+}
+
+/// The number of bytes freed by unlinking a file,
+/// which is zero if other hard links to it remain.
+#[cfg(unix)]
+fn freed_size_on_unlink(meta: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    match meta.nlink() {
+        1 => meta.len(),
+        _ => 0,
+    }
+}
+#[cfg(not(unix))]
+fn freed_size_on_unlink(meta: &std::fs::Metadata) -> u64 {
+    meta.len()
+}
+
+/// This function makes byte-identical members of each duplicate group
+/// share their storage on disk, leaving every path intact.
+///
+/// Members are compared by size and checksum; within each set of
+/// identical files, the one with the smallest path is used as the source.
+/// Groups without any byte-identical members are left untouched.
+///
+/// The result of each group is logged to the console,
+/// along with any filesystems that do not support extent sharing.
+///
+/// In a dry run (`dry-run`), identical files are found,
+/// but their storage is not shared.
+#[cfg(target_os = "linux")]
+pub fn share_extents(groups: &[DupGroup], roots: &[PathBuf], sub_matches: &ArgMatches) {
+    use std::{collections::HashMap, fs, io::ErrorKind, os::unix::fs::MetadataExt};
+
+    // get dry run option
+    let dry_run = sub_matches.is_present("dry-run");
+
+    match dry_run {
+        true => println!(
+            "Planning to share storage between byte-identical images in {} group(s)...",
+            groups.len()
+        ),
+        false => println!(
+            "Sharing storage between byte-identical images in {} group(s)...",
+            groups.len()
+        ),
+    }
+
+    let (mut total_bytes, mut shared_count, mut failed_count) = (0, 0, 0);
+    let mut unsupported_fs = HashMap::new(); // device ID -> sample path
+    for (id, group) in groups.iter().enumerate() {
+        // partition members into sets of identical files
+        let mut identical: HashMap<_, Vec<&Path>> = HashMap::new();
+        for &(path, _) in group.members.iter() {
+            match fs::metadata(path).and_then(|meta| Ok((meta.len(), file_checksum(path)?))) {
+                Ok(key) => identical.entry(key).or_default().push(path),
+                Err(e) => println!("  Group {}: failed to read {:?}: {}", id + 1, path, e),
+            }
+        }
+        let identical_sets: Vec<_> = identical
+            .into_values()
+            .filter(|paths| paths.len() > 1)
+            .map(|paths| paths.into_iter().sorted().collect_vec())
+            .sorted()
+            .collect();
+        if identical_sets.is_empty() {
+            println!("  Group {}: no byte-identical images, skipped", id + 1);
+            continue;
+        }
+
+        let (mut group_bytes, mut group_files, mut group_errors) = (0, 0, 0);
+        for paths in identical_sets.iter() {
+            let (src, dests) = paths.split_first().unwrap(); // each set has at least 2 members
+            for &dest in dests {
+                if let (Ok(src_meta), Ok(dest_meta)) = (fs::metadata(src), fs::metadata(dest)) {
+                    if is_same_file(&src_meta, &dest_meta) {
+                        continue; // hard links already share storage
+                    }
+                }
+                if dry_run {
+                    group_bytes += fs::metadata(dest).map_or(0, |meta| meta.len());
+                    group_files += 1;
+                    continue;
+                }
+                match dedupe_file(src, dest) {
+                    Ok(bytes) => {
+                        group_bytes += bytes;
+                        group_files += 1;
+                    }
+                    Err(e) if e.kind() == ErrorKind::Unsupported => {
+                        if let Ok(meta) = fs::metadata(dest) {
+                            unsupported_fs.entry(meta.dev()).or_insert_with(|| dest.to_path_buf());
+                        }
+                        group_errors += 1;
+                    }
+                    Err(e) => {
+                        println!("  Group {}: failed to share {:?} with {:?}: {}", id + 1, dest, src, e);
+                        group_errors += 1;
+                    }
+                }
+            }
+        }
+        let verb = match dry_run {
+            true => "would deduplicate",
+            false => "deduplicated",
+        };
+        match group_errors {
+            0 => println!(
+                "  Group {}: {} {} bytes across {} image(s), sharing storage with {}",
+                id + 1,
+                verb,
+                group_bytes,
+                group_files,
+                identical_sets
+                    .iter()
+                    .map(|paths| fmt_with_root(paths[0], roots))
+                    .join(", ")
+            ),
+            n => println!(
+                "  Group {}: {} {} bytes across {} image(s); {} failed",
+                id + 1,
+                verb,
+                group_bytes,
+                group_files,
+                n
+            ),
+        }
+        total_bytes += group_bytes;
+        shared_count += group_files;
+        failed_count += group_errors;
+    }
+
+    match dry_run {
+        true => println!(
+            "Would deduplicate {} bytes across {} image(s); nothing was changed (dry run)",
+            total_bytes, shared_count
+        ),
+        false => println!(
+            "Deduplicated {} bytes across {} image(s); {} failed",
+            total_bytes, shared_count, failed_count
+        ),
+    }
+    for path in unsupported_fs.values().sorted() {
+        println!("  The filesystem containing {:?} does not support extent sharing", path);
+    }
+}
+
+/// This function writes a plan file (`plan`), listing the action to take
+/// on each member of each duplicate group, for review before `apply`.
+///
+/// Keepers are listed with the `keep` action,
+/// and all other members with the selected action (`action`).
+///
+/// Returns Err if the expected arguments (`plan`, `action`)
+/// are not found in `sub_matches`, or if the plan cannot be written.
+pub fn save_plan(groups: &[DupGroup], keepers: &[usize], sub_matches: &ArgMatches) -> Result<(), String> {
+    // get plan options
+    let plan_path = Path::new(sub_matches.value_of("plan").ok_or("plan not specified")?);
+    let action = parse_plan_action(sub_matches.value_of("action").ok_or("action not specified")?).unwrap(); // validation provided by clap
+
+    let groups = groups
+        .iter()
+        .zip(keepers.iter())
+        .enumerate()
+        .map(|(id, (group, &keeper))| {
+            let files = (0..group.members.len())
+                .map(|n| {
+                    let (path, distance) = (group.members[n].0, group.dist(n, keeper));
+                    let action = match n == keeper {
+                        true => PlanAction::Keep,
+                        false => action,
+                    };
+                    PlanFile::of(path, action, distance).map_err(|e| format!("Cannot read {:?}: {}", path, e))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(PlanGroup {
+                id: id + 1,
+                keeper: files[keeper].path.clone(),
+                files,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    write_plan(plan_path, &Plan { groups })?;
+    eprintln!("Saved plan to [{}]", plan_path.display());
+
+    Ok(())
+}
 
-    // test write to destination directory
-    test_write_to_dir(Path::new(dest_dir)).map_err(|e| e.to_string())?;
+/// This function applies a plan file written by [`save_plan`],
+/// possibly edited by the user since.
+///
+/// Before anything is modified, every file in the plan is checked
+/// to still have the size and modification time recorded at scan time,
+/// and every keeper is checked to be kept. If any check fails,
+/// the problems are logged and nothing is done.
+///
+/// Files are then moved, deleted and linked using the same options
+/// as `move-duplicates`, `delete-duplicates` and `link-duplicates`.
+///
+/// Returns Err if the expected argument (`plan`) is not found in `sub_matches`,
+/// if the plan cannot be read or fails its checks, or if any action fails as a whole.
+pub fn apply_plan(sub_matches: &ArgMatches) -> Result<(), String> {
+    // get plan option
+    let plan_path = Path::new(sub_matches.value_of("plan").ok_or("plan not specified")?);
+    let plan = read_plan(plan_path).map_err(|e| format!("Cannot read plan: {}", e))?;
 
-    println!("Moving {} image(s) into [{}]...", files.len(), dest_dir);
+    println!(
+        "Checking {} file(s) in {} group(s) against the plan...",
+        plan.groups.iter().map(|group| group.files.len()).sum::<usize>(),
+        plan.groups.len()
+    );
 
-    // move all, retaining original filenames
-    for &from_path in files.iter() {
-        let mut dest_path = Path::new(dest_dir).to_path_buf();
-        dest_path.push(get_filename_unchecked(from_path));
-        if let Err(e) = mv(from_path, dest_path) {
-            println!("Failed to move an image: {:?}", e);
+    // check everything before doing anything
+    let mut problems = vec![];
+    for group in plan.groups.iter() {
+        if let Err(e) = group.check_keeper() {
+            problems.push(format!("Group {}: {}", group.id, e));
+        }
+        for file in group.files.iter() {
+            if let Err(e) = file.check_unchanged() {
+                problems.push(format!("Group {}: {:?}: {}", group.id, file.path, e));
+            }
+        }
+    }
+    if !problems.is_empty() {
+        for problem in problems.iter() {
+            println!("  {}", problem);
         }
+        return Err(format!(
+            "{} problem(s) found in the plan; nothing was changed",
+            problems.len()
+        ));
+    }
+
+    // sort files by action
+    let files_with = |action: PlanAction| -> Vec<_> {
+        plan.groups
+            .iter()
+            .flat_map(|group| {
+                group
+                    .files
+                    .iter()
+                    .filter(move |file| file.action == action)
+                    .map(move |file| Duplicate {
+                        path: &file.path,
+                        keeper: &group.keeper,
+                        group_id: group.id,
+                        distance: file.distance,
+                    })
+            })
+            .collect()
+    };
+    let (to_move, to_delete, to_link) = (
+        files_with(PlanAction::Move),
+        files_with(PlanAction::Delete),
+        files_with(PlanAction::Link),
+    );
+    println!(
+        "Plan: keep {}, move {}, delete {}, link {} file(s)",
+        files_with(PlanAction::Keep).len(),
+        to_move.len(),
+        to_delete.len(),
+        to_link.len()
+    );
+    if !to_move.is_empty() && !sub_matches.is_present("destination") {
+        return Err("The plan moves files, but no destination was given; nothing was changed".into());
+    }
+
+    if !to_move.is_empty() {
+        move_all(&to_move, sub_matches)?;
+    }
+    if !to_delete.is_empty() {
+        delete_all(&to_delete, sub_matches)?;
+    }
+    if !to_link.is_empty() {
+        link_all(&to_link, sub_matches)?;
+    }
+
+    Ok(())
+}
+
+/// This function writes a POSIX shell script (`script`) with one command
+/// per non-keeper, performing the selected action (`action`), so that it can be
+/// reviewed before being run. Each command is preceded by a comment
+/// naming its group, the group's keeper, and its distance to the keeper.
+///
+/// Moves go into the destination directory (`destination`), renamed with
+/// a numeric suffix where names collide. Links are of the selected type (`link-type`),
+/// and only replace files whose content is identical to their keeper's.
+///
+/// Returns Err if the expected arguments (`script`, `action`, `link-type`)
+/// are not found in `sub_matches`, if moving without a destination,
+/// if a path cannot be quoted (i.e. is not valid UTF-8),
+/// or if the script cannot be written.
+pub fn save_script(groups: &[DupGroup], keepers: &[usize], sub_matches: &ArgMatches) -> Result<(), String> {
+    use std::fmt::Write;
+
+    // get script options
+    let script_path = Path::new(sub_matches.value_of("script").ok_or("script not specified")?);
+    let action = parse_plan_action(sub_matches.value_of("action").ok_or("action not specified")?).unwrap(); // validation provided by clap
+    let link_type = parse_link_type(sub_matches.value_of("link-type").ok_or("link-type not specified")?).unwrap(); // validation provided by clap
+    let absolute = |path: &Path| std::path::absolute(path).map_err(|e| format!("Cannot resolve {:?}: {}", path, e));
+    let dest_dir = match (action, sub_matches.value_of("destination")) {
+        (PlanAction::Move, None) => return Err("Moving requires a destination directory (--destination)".into()),
+        (_, dest_dir) => dest_dir.map(|dir| absolute(Path::new(dir))).transpose()?,
+    };
+
+    let mut script = SCRIPT_HEADER.to_string();
+    if let (PlanAction::Move, Some(dest_dir)) = (action, &dest_dir) {
+        writeln!(script, "\nmkdir -p -- {}", quote(dest_dir)?).unwrap(); // writing to String is infallible
+    }
+
+    let mut claimed = HashSet::new();
+    for (id, (group, &keeper)) in groups.iter().zip(keepers.iter()).enumerate() {
+        let keeper_path = absolute(group.members[keeper].0)?;
+        script.push('\n');
+        for n in (0..group.members.len()).filter(|&n| n != keeper) {
+            let path = absolute(group.members[n].0)?;
+            // paths in comments are escaped, so that newlines cannot end the comment
+            writeln!(
+                script,
+                "# Group {}, keeper {:?}, distance {}",
+                id + 1,
+                keeper_path,
+                group.dist(n, keeper)
+            )
+            .unwrap(); // writing to String is infallible
+
+            let command = match action {
+                PlanAction::Keep => format!("# keep {:?}", path),
+                PlanAction::Delete => ScriptCommand::Remove { path: &path }.render()?,
+                PlanAction::Move => {
+                    let dest_dir = dest_dir.as_deref().unwrap(); // checked above
+                    let to = resolve_dest_path(&path, dest_dir, CollisionStrategy::Suffix, &mut claimed)?.unwrap(); // suffix never skips
+                    ScriptCommand::Move { from: &path, to: &to }.render()?
+                }
+                // linking would lose the content of files that merely look alike
+                PlanAction::Link
+                    if !has_same_content(&path, &keeper_path)
+                        .map_err(|e| format!("Cannot read {:?}: {}", path, e))? =>
+                {
+                    format!("# content differs from the keeper, not linked: {:?}", path)
+                }
+                PlanAction::Link => match predict_link(&path, &keeper_path, link_type) {
+                    Ok(LinkOutcome::Unchanged) => format!("# already a hard link to the keeper: {:?}", path),
+                    Ok(LinkOutcome::Hard) => ScriptCommand::HardLink {
+                        path: &path,
+                        target: &keeper_path,
+                    }
+                    .render()?,
+                    Ok(LinkOutcome::Symbolic) => {
+                        let target = symlink_target(&path, &keeper_path).map_err(|e| e.to_string())?;
+                        ScriptCommand::SymLink {
+                            path: &path,
+                            target: &target,
+                        }
+                        .render()?
+                    }
+                    Err(e) => return Err(format!("Cannot link {:?}: {}", path, e)),
+                },
+            };
+            writeln!(script, "{}", command).unwrap(); // writing to String is infallible
+        }
+    }
+
+    std::fs::write(script_path, script).map_err(|e| e.to_string())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = std::fs::set_permissions(script_path, std::fs::Permissions::from_mode(0o755));
     }
+    eprintln!("Saved script to [{}]", script_path.display());
 
     Ok(())
 }
+
+/// This function lets the user review the duplicate groups in an interactive
+/// terminal UI, then moves and deletes the images as marked,
+/// using `move_all` and `delete_all`.
+///
+/// Each group's keeper is initially marked to keep, and all other members
+/// to move if a destination directory (`destination`) is given, or to delete otherwise.
+///
+/// Returns Err if the review fails (e.g. without a terminal),
+/// or if moving or deleting fails.
+pub fn review_groups(
+    groups: &[DupGroup],
+    keepers: &[usize],
+    roots: &[PathBuf],
+    sub_matches: &ArgMatches,
+) -> Result<(), String> {
+    let can_move = sub_matches.is_present("destination");
+    let removal = match can_move {
+        true => Mark::Move,
+        false => Mark::Delete,
+    };
+    let marks = groups
+        .iter()
+        .zip(keepers.iter())
+        .map(|(group, &keeper)| {
+            (0..group.members.len())
+                .map(|n| match n == keeper {
+                    true => Mark::Keep,
+                    false => removal,
+                })
+                .collect()
+        })
+        .collect();
+
+    let marks = match review_marks(groups, marks, roots, can_move).map_err(|e| e.to_string())? {
+        Some(marks) => marks,
+        None => {
+            println!("Review cancelled; nothing was changed");
+            return Ok(());
+        }
+    };
+
+    // sort files by mark; the first image kept in a group is the keeper of the others
+    let marked_with = |mark: Mark| -> Vec<_> {
+        groups
+            .iter()
+            .zip(marks.iter())
+            .enumerate()
+            .flat_map(|(id, (group, marks))| {
+                let keeper = marks.iter().position(|&m| m == Mark::Keep);
+                (0..group.members.len())
+                    .filter(move |&n| marks[n] == mark)
+                    .map(move |n| {
+                        let keeper = keeper.unwrap_or(n); // only kept and non-duplicate images may lack a keeper
+                        Duplicate {
+                            path: group.members[n].0,
+                            keeper: group.members[keeper].0,
+                            group_id: id + 1,
+                            distance: group.dist(n, keeper),
+                        }
+                    })
+            })
+            .collect()
+    };
+    let (to_move, to_delete) = (marked_with(Mark::Move), marked_with(Mark::Delete));
+    println!(
+        "Review: keep {}, move {}, delete {} image(s); {} not duplicates",
+        marked_with(Mark::Keep).len(),
+        to_move.len(),
+        to_delete.len(),
+        marked_with(Mark::NotDuplicate).len()
+    );
+
+    if !to_move.is_empty() {
+        move_all(&to_move, sub_matches)?;
+    }
+    if !to_delete.is_empty() {
+        delete_all(&to_delete, sub_matches)?;
+    }
+
+    Ok(())
+}
+
+/// This function writes an HTML report (`report`) of the duplicate groups,
+/// with a thumbnail of each image, for review in a browser.
+///
+/// Returns Err if the expected arguments (`report`, `algorithm`, `hash-size`)
+/// are not found in `sub_matches`, or if the report cannot be written.
+pub fn save_report(groups: &[DupGroup], concurrency: usize, sub_matches: &ArgMatches) -> Result<(), String> {
+    // get report options
+    let report_path = Path::new(sub_matches.value_of("report").ok_or("report not specified")?);
+    let settings = HashSettings {
+        algorithm: sub_matches.value_of("algorithm").ok_or("algorithm not specified")?,
+        hash_size: parse_hash_size(sub_matches.value_of("hash-size").ok_or("hash-size not specified")?).unwrap(), // validation provided by clap
+    };
+
+    // only duplicates are shown, so they are decoded again rather than
+    // keeping a thumbnail of every image while hashing;
+    // this is done for a batch of groups at a time, and their thumbnails are dropped once written
+    let image_count: usize = groups.iter().map(|group| group.members.len()).sum();
+    eprintln!("Making thumbnails of {} image(s) for the report...", image_count);
+    let batch_size = concurrency * 4;
+    let file = std::fs::File::create(report_path).map_err(|e| e.to_string())?;
+    let mut out = std::io::BufWriter::new(file);
+    let write_res = (|| {
+        write_report_start(&mut out, groups, &settings)?;
+        for (batch_idx, batch) in groups.chunks(batch_size).enumerate() {
+            let paths: Vec<_> = batch
+                .iter()
+                .flat_map(|group| group.members.iter().map(|&(path, _)| path))
+                .collect();
+            let mut thumbnails = calc_thumbnails(&paths, REPORT_THUMBNAIL_SIZE, concurrency).into_iter();
+            for (n, group) in batch.iter().enumerate() {
+                let group_thumbnails: Vec<_> = thumbnails.by_ref().take(group.members.len()).collect();
+                write_report_group(&mut out, batch_idx * batch_size + n + 1, group, &group_thumbnails)?;
+            }
+        }
+        write_report_end(&mut out)
+    })();
+    write_res.map_err(|e| e.to_string())?;
+    eprintln!("Saved report to [{}]", report_path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clap_def::build_app, journal::Journal, test_util::test_dir};
+    use std::fs;
+
+    #[test]
+    fn undo_restores_moves_without_overwriting() {
+        let dir = test_dir("undo");
+        let journal_path = dir.join("moves.jsonl");
+        let mut journal = Journal::open(&journal_path).unwrap();
+        fs::create_dir_all(dir.join("dups")).unwrap();
+        for name in ["a.jpg", "b.jpg", "c.jpg"] {
+            let (source, destination) = (dir.join("orig").join(name), dir.join("dups").join(name));
+            fs::write(&destination, name).unwrap();
+            journal.record(&JournalEntry::now(&source, &destination, 1, 0)).unwrap();
+        }
+        // the original path of b is reoccupied, and c has disappeared from its destination
+        fs::create_dir_all(dir.join("orig")).unwrap();
+        fs::write(dir.join("orig/b.jpg"), "new b").unwrap();
+        fs::remove_file(dir.join("dups/c.jpg")).unwrap();
+
+        let matches = build_app().get_matches_from(vec!["img_dedup", "undo", journal_path.to_str().unwrap()]);
+        let sub_matches = matches.subcommand_matches("undo").unwrap();
+        undo_moves(sub_matches).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("orig/a.jpg")).unwrap(), "a.jpg");
+        assert!(!dir.join("dups/a.jpg").exists());
+        assert_eq!(fs::read_to_string(dir.join("orig/b.jpg")).unwrap(), "new b");
+        assert_eq!(fs::read_to_string(dir.join("dups/b.jpg")).unwrap(), "b.jpg");
+        // the unrestored entries remain, for a retry
+        let remaining: Vec<_> = read_journal(&journal_path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.destination)
+            .collect();
+        assert_eq!(remaining, vec![dir.join("dups/b.jpg"), dir.join("dups/c.jpg")]);
+
+        // once the conflict is resolved, a retry restores the rest and removes the journal
+        fs::remove_file(dir.join("orig/b.jpg")).unwrap();
+        fs::write(dir.join("dups/c.jpg"), "c.jpg").unwrap();
+        undo_moves(sub_matches).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("orig/b.jpg")).unwrap(), "b.jpg");
+        assert_eq!(fs::read_to_string(dir.join("orig/c.jpg")).unwrap(), "c.jpg");
+        assert!(!journal_path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_skips_differing_content() {
+        let dir = test_dir("link-all");
+        let (keeper, same, differs) = (dir.join("keep.jpg"), dir.join("same.jpg"), dir.join("differs.jpg"));
+        fs::write(&keeper, "content").unwrap();
+        fs::write(&same, "content").unwrap();
+        fs::write(&differs, "looks alike").unwrap();
+        let duplicates: Vec<_> = [&same, &differs]
+            .iter()
+            .map(|path| Duplicate {
+                path,
+                keeper: &keeper,
+                group_id: 1,
+                distance: 0,
+            })
+            .collect();
+
+        let matches = build_app().get_matches_from(vec![
+            "img_dedup",
+            dir.to_str().unwrap(),
+            "link-duplicates",
+            "--link-type",
+            "hard",
+        ]);
+        link_all(&duplicates, matches.subcommand_matches("link-duplicates").unwrap()).unwrap();
+
+        let is_linked = |path: &Path| is_same_file(&fs::metadata(path).unwrap(), &fs::metadata(&keeper).unwrap());
+        assert!(is_linked(&same));
+        assert!(!is_linked(&differs));
+        assert_eq!(fs::read_to_string(&differs).unwrap(), "looks alike");
+    }
+
+    #[test]
+    fn apply_refuses_plan_without_kept_keeper() {
+        let dir = test_dir("apply-keeper");
+        let (keeper, dup) = (dir.join("keep.jpg"), dir.join("dup.jpg"));
+        fs::write(&keeper, "content").unwrap();
+        fs::write(&dup, "content").unwrap();
+        let plan = Plan {
+            groups: vec![PlanGroup {
+                id: 1,
+                keeper: keeper.clone(),
+                files: vec![
+                    PlanFile::of(&keeper, PlanAction::Delete, 0).unwrap(),
+                    PlanFile::of(&dup, PlanAction::Link, 0).unwrap(),
+                ],
+            }],
+        };
+        let plan_path = dir.join("plan.toml");
+        write_plan(&plan_path, &plan).unwrap();
+
+        let matches = build_app().get_matches_from(vec!["img_dedup", "apply", plan_path.to_str().unwrap()]);
+        let err = apply_plan(matches.subcommand_matches("apply").unwrap()).unwrap_err();
+        assert!(err.ends_with("nothing was changed"), "{}", err);
+        assert_eq!(fs::read_to_string(&keeper).unwrap(), "content");
+        assert!(!fs::symlink_metadata(&dup).unwrap().file_type().is_symlink());
+        assert!(!is_same_file(
+            &fs::metadata(&dup).unwrap(),
+            &fs::metadata(&keeper).unwrap()
+        ));
+    }
+}
@@ -0,0 +1,11 @@
+//! This module contains fixtures shared by the unit tests of all modules.
+
+use std::{env, fs, path::PathBuf, process};
+
+/// Create an empty directory for a test, unique to the test and process.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("img-dedup-test-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
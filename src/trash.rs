@@ -0,0 +1,202 @@
+//! This module implements moving files to the trash, following the
+//! FreeDesktop.org trash specification, so that they can be restored
+//! from any compliant desktop file manager.
+//!
+//! See https://specifications.freedesktop.org/trash-spec/trashspec-latest.html.
+//!
+//! All files are moved to the home trash. Files on other filesystems
+//! are copied there and verified before being removed, as the
+//! specification permits.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::io::{move_file, MoveMethod};
+
+/// The home trash directory: `$XDG_DATA_HOME/Trash`,
+/// falling back to `~/.local/share/Trash`.
+pub fn home_trash_dir() -> Result<PathBuf, String> {
+    std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share")))
+        .map(|data_home| data_home.join("Trash"))
+        .ok_or_else(|| "Cannot locate the trash: neither XDG_DATA_HOME nor HOME is set".to_string())
+}
+
+/// Percent-encode a path for the `Path` key of a `.trashinfo` file,
+/// leaving only unreserved characters and separators as-is.
+fn encode_path(path: &Path) -> String {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().into_owned().into_bytes();
+
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// This function moves a file into the trash directory,
+/// alongside a `.trashinfo` file recording its original path and deletion time.
+///
+/// Returns the path of the file in the trash, and how it was moved.
+pub fn trash_file(path: &Path, trash_dir: &Path) -> std::io::Result<(PathBuf, MoveMethod)> {
+    let files_dir = trash_dir.join("files");
+    let info_dir = trash_dir.join("info");
+    fs::create_dir_all(&files_dir)?;
+    fs::create_dir_all(&info_dir)?;
+
+    let abs_path = std::path::absolute(path)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let (stem, ext) = match file_name.rfind('.') {
+        Some(idx) if idx > 0 => file_name.split_at(idx),
+        _ => (file_name.as_str(), ""),
+    };
+
+    // the info file is created first and exclusively, which reserves the name
+    let mut n = 0;
+    let (trash_name, mut info_file) = loop {
+        let name = match n {
+            0 => file_name.clone(),
+            n => format!("{}.{}{}", stem, n, ext),
+        };
+        n += 1;
+        if files_dir.join(&name).exists() {
+            continue;
+        }
+        let info_path = info_dir.join(format!("{}.trashinfo", name));
+        match OpenOptions::new().write(true).create_new(true).open(info_path) {
+            Ok(file) => break (name, file),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    let info_path = info_dir.join(format!("{}.trashinfo", trash_name));
+    let trashed_path = files_dir.join(&trash_name);
+
+    let trash_res = (|| {
+        write!(
+            info_file,
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            encode_path(&abs_path),
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S")
+        )?;
+        info_file.sync_all()?;
+        move_file(path, &trashed_path, false)
+    })();
+
+    match trash_res {
+        Ok(method) => Ok((trashed_path, method)),
+        Err(e) => {
+            let _ = fs::remove_file(&info_path);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn encode_unreserved() {
+        assert_eq!(
+            encode_path(Path::new("/home/u/IMG_0001-a.b~.jpg")),
+            "/home/u/IMG_0001-a.b~.jpg"
+        );
+    }
+
+    #[test]
+    fn encode_reserved() {
+        assert_eq!(
+            encode_path(Path::new("/a b/%#?&=+.jpg")),
+            "/a%20b/%25%23%3F%26%3D%2B.jpg"
+        );
+        assert_eq!(encode_path(Path::new("/new\nline")), "/new%0Aline");
+    }
+
+    #[test]
+    fn encode_multibyte() {
+        assert_eq!(encode_path(Path::new("/été.jpg")), "/%C3%A9t%C3%A9.jpg");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn encode_non_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+        assert_eq!(
+            encode_path(Path::new(OsStr::from_bytes(b"/caf\xe9.jpg"))),
+            "/caf%E9.jpg"
+        );
+    }
+
+    #[test]
+    fn trash_records_original_path() {
+        let dir = test_dir("trash");
+        let (path, trash_dir) = (dir.join("a b.jpg"), dir.join("Trash"));
+        fs::write(&path, "a").unwrap();
+
+        let (trashed_path, _) = trash_file(&path, &trash_dir).unwrap();
+        assert!(!path.exists());
+        assert_eq!(trashed_path, trash_dir.join("files/a b.jpg"));
+        assert_eq!(fs::read_to_string(&trashed_path).unwrap(), "a");
+        let info = fs::read_to_string(trash_dir.join("info/a b.jpg.trashinfo")).unwrap();
+        assert!(info.starts_with("[Trash Info]\nPath=/"), "{}", info);
+        assert!(info.contains("/a%20b.jpg\nDeletionDate="), "{}", info);
+
+        // a second file of the same name gets another name in the trash
+        fs::write(&path, "b").unwrap();
+        let (trashed_path, _) = trash_file(&path, &trash_dir).unwrap();
+        assert_ne!(trashed_path, trash_dir.join("files/a b.jpg"));
+        assert_eq!(fs::read_to_string(&trashed_path).unwrap(), "b");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn trash_fails_if_info_is_read_only() {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+        let dir = test_dir("trash-read-only");
+        let (path, trash_dir) = (dir.join("a.jpg"), dir.join("Trash"));
+        fs::write(&path, "a").unwrap();
+        fs::create_dir_all(trash_dir.join("info")).unwrap();
+        fs::set_permissions(trash_dir.join("info"), Permissions::from_mode(0o555)).unwrap();
+
+        // permissions are not enforced for privileged users
+        let probe = trash_dir.join("info/probe");
+        if fs::write(&probe, "").is_ok() {
+            fs::remove_file(&probe).unwrap();
+            fs::set_permissions(trash_dir.join("info"), Permissions::from_mode(0o755)).unwrap();
+            return;
+        }
+
+        let trash_res = trash_file(&path, &trash_dir);
+        fs::set_permissions(trash_dir.join("info"), Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(trash_res.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a");
+    }
+
+    #[test]
+    fn trash_fails_if_info_cannot_be_created() {
+        // the name of the info file is too long, even though the file's name is not
+        let dir = test_dir("trash-long-name");
+        let (path, trash_dir) = (dir.join(format!("{}.jpg", "a".repeat(250))), dir.join("Trash"));
+        fs::write(&path, "a").unwrap();
+
+        assert!(trash_file(&path, &trash_dir).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a");
+        assert_eq!(fs::read_dir(trash_dir.join("info")).unwrap().count(), 0);
+    }
+}